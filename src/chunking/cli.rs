@@ -4,9 +4,15 @@ use camino::Utf8PathBuf;
 use clap::{Args, ValueEnum};
 
 use crate::{
-    chunking::{Chunker, ostreext::OstreeExtChunker},
+    chunking::{
+        Chunker,
+        ostreext::OstreeExtChunker,
+        volatility::{AverageInterval, Bayesian, ExponentialDecay, RecentChanges, Volatility},
+    },
     pkgdb::PackageIndex,
-    rpm_ostree::{ContainerEncapsulateOpts, container_encapsulate},
+    rpm_ostree::{
+        ContainerEncapsulateOpts, PathTranslation, container_encapsulate, reconcile_sizes,
+    },
 };
 
 const MAX_LAYERS_DEFAULT: NonZero<u32> = NonZero::new(64).unwrap();
@@ -18,9 +24,44 @@ pub(crate) enum ChunkingStrategy {
 }
 
 impl ChunkingStrategy {
-    pub fn get_chunker(&self) -> Box<dyn Chunker> {
+    pub fn get_chunker(
+        &self,
+        volatility: Box<dyn Volatility>,
+        path_translation: PathTranslation,
+    ) -> Box<dyn Chunker> {
         match self {
-            ChunkingStrategy::OstreeExt => Box::new(OstreeExtChunker::new()),
+            ChunkingStrategy::OstreeExt => {
+                Box::new(OstreeExtChunker::new(volatility, path_translation))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub(crate) enum VolatilityModel {
+    /// Inverse of the average interval between changes
+    AverageInterval,
+    /// Number of changes, exponentially decayed by their age
+    ExponentialDecay,
+    /// Number of changes within a recent window
+    RecentChanges,
+    /// Bayesian update rate estimate with the rate of the package's source group as prior
+    Bayesian,
+}
+
+impl VolatilityModel {
+    pub fn get_model(&self, opts: &GenerateChunkedOCIOpts) -> Box<dyn Volatility> {
+        match self {
+            VolatilityModel::AverageInterval => Box::new(AverageInterval),
+            VolatilityModel::ExponentialDecay => Box::new(ExponentialDecay {
+                half_life: opts.volatility_half_life,
+            }),
+            VolatilityModel::RecentChanges => Box::new(RecentChanges {
+                window: opts.volatility_window,
+            }),
+            VolatilityModel::Bayesian => Box::new(Bayesian {
+                prior_weight: opts.volatility_prior_weight,
+            }),
         }
     }
 }
@@ -36,6 +77,66 @@ pub(crate) struct GenerateChunkedOCIOpts {
     pub package_index: Utf8PathBuf,
     #[clap(long, required = false, default_value = "ostree-ext")]
     pub chunking_strategy: ChunkingStrategy,
+    #[clap(
+        long,
+        required = false,
+        default_value = "average-interval",
+        help = "Model used to estimate how frequently packages change"
+    )]
+    pub volatility_model: VolatilityModel,
+    #[clap(
+        long,
+        required = false,
+        default_value_t = 30,
+        help = "Half-life of changes in days (or builds) for the exponential-decay volatility model"
+    )]
+    pub volatility_half_life: u32,
+    #[clap(
+        long,
+        required = false,
+        default_value_t = 30,
+        help = "Window in days (or builds) for the recent-changes volatility model"
+    )]
+    pub volatility_window: u32,
+    #[clap(
+        long,
+        required = false,
+        default_value_t = 30,
+        help = "Weight of the source group prior in days (or builds) for the bayesian volatility model"
+    )]
+    pub volatility_prior_weight: u32,
+    #[clap(
+        long = "translate-path",
+        required = false,
+        help = "Look up packaged files below FROM at TO in the commit (FROM=TO), in addition to the default translations"
+    )]
+    pub translate_paths: Vec<String>,
+    #[clap(
+        long,
+        required = false,
+        help = "Do not apply the default path translations (/etc to /usr/etc, /opt to /usr/lib/opt, usr-merge, ...)"
+    )]
+    pub no_default_path_translations: bool,
+    #[clap(
+        long,
+        required = false,
+        help = "Report packages whose size according to the package manager differs from the content they own in the commit. This does not change the image, as layers are always packed by content size"
+    )]
+    pub reconcile_sizes: bool,
+    #[clap(
+        long,
+        required = false,
+        default_value_t = 25,
+        help = "Report packages whose size differs by more than this many percent (and at least 1 MiB) with --reconcile-sizes"
+    )]
+    pub size_discrepancy_threshold: u32,
+    #[clap(
+        long,
+        required = false,
+        requires = "reconcile_sizes",
+        help = "Write the package index with the sizes of the content in the commit to this path"
+    )]
+    pub output_reconciled_package_index: Option<Utf8PathBuf>,
 
     #[command(flatten)]
     pub ostree_encapsulate: ContainerEncapsulateOpts,
@@ -45,7 +146,16 @@ impl GenerateChunkedOCIOpts {
     pub(crate) fn run(mut self) -> Result<(), anyhow::Error> {
         let package_index =
             serde_json::from_reader::<_, Vec<PackageIndex>>(File::open(&self.package_index)?)?;
-        let mut chunker = self.chunking_strategy.get_chunker();
+        // Removed packages are only kept in the index for their history
+        let (tombstones, mut package_index): (Vec<_>, Vec<_>) = package_index
+            .into_iter()
+            .partition(|package| package.is_removed());
+        let volatility = self.volatility_model.get_model(&self);
+        let path_translation =
+            PathTranslation::new(&self.translate_paths, !self.no_default_path_translations)?;
+        let mut chunker = self
+            .chunking_strategy
+            .get_chunker(volatility, path_translation);
         let max_layers = self
             .ostree_encapsulate
             .max_layers
@@ -56,6 +166,23 @@ impl GenerateChunkedOCIOpts {
             &self.ostree_encapsulate.repo,
             &self.ostree_encapsulate.ostree_ref,
         )?;
+        // ostree-ext already packs layers by the size of the content objects, so the reconciled sizes only need to
+        // be reported and persisted for later use
+        if self.reconcile_sizes {
+            let discrepancies =
+                reconcile_sizes(&mut package_index, &meta, self.size_discrepancy_threshold);
+            for discrepancy in &discrepancies {
+                println!(
+                    "  {}: {} bytes recorded, {} bytes in the commit",
+                    discrepancy.name, discrepancy.recorded, discrepancy.actual
+                );
+            }
+            if let Some(output_package_index) = &self.output_reconciled_package_index {
+                package_index.extend(tombstones);
+                serde_json::to_writer(File::create_new(output_package_index)?, &package_index)?;
+                tracing::trace!("Reconciled Package Index written to disk");
+            }
+        }
         self.ostree_encapsulate.max_layers = Some(max_layers);
         container_encapsulate(self.ostree_encapsulate, &meta)
     }
@@ -7,6 +7,7 @@ use crate::pkgdb::PackageIndex;
 
 pub(crate) mod cli;
 pub(crate) mod ostreext;
+pub(crate) mod volatility;
 
 pub(crate) trait Chunker {
     fn chunk(
@@ -4,16 +4,22 @@ use camino::Utf8Path;
 use ostree_ext::chunking::ObjectMetaSized;
 
 use crate::{
-    chunking::Chunker,
+    chunking::{Chunker, volatility::Volatility},
     pkgdb::PackageIndex,
-    rpm_ostree::{generate_mapping, open_ostree},
+    rpm_ostree::{PathTranslation, generate_mapping, open_ostree},
 };
 
-pub(crate) struct OstreeExtChunker;
+pub(crate) struct OstreeExtChunker {
+    volatility: Box<dyn Volatility>,
+    path_translation: PathTranslation,
+}
 
 impl OstreeExtChunker {
-    pub fn new() -> Self {
-        OstreeExtChunker {}
+    pub fn new(volatility: Box<dyn Volatility>, path_translation: PathTranslation) -> Self {
+        OstreeExtChunker {
+            volatility,
+            path_translation,
+        }
     }
 }
 
@@ -26,7 +32,13 @@ impl Chunker for OstreeExtChunker {
         commit: &str,
     ) -> Result<ObjectMetaSized, anyhow::Error> {
         let (repo, root, _rev) = open_ostree(repo, commit)?;
-        let meta = generate_mapping(&repo, &root, packages)?;
+        let meta = generate_mapping(
+            &repo,
+            &root,
+            packages,
+            self.volatility.as_ref(),
+            &self.path_translation,
+        )?;
         Ok(meta)
     }
 }
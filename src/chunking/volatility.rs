@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+
+use crate::pkgdb::{ChangeIdMode, PackageIndex};
+
+/// All models estimate the number of updates per day (or build). The rates are scaled by this factor before they
+/// are handed to ostree-ext, which only takes integers, so 1000 means one update per day.
+const RATE_SCALE: f64 = 1000.0;
+
+fn rate_to_frequency(rate: f64) -> u32 {
+    (rate * RATE_SCALE).round().clamp(0.0, f64::from(u32::MAX)) as u32
+}
+
+/// Age of a change in days (or builds), relative to the current change.
+fn age(change: u64, current_change: u64, change_id_mode: ChangeIdMode) -> f64 {
+    current_change.saturating_sub(change) as f64 / change_id_mode.period() as f64
+}
+
+/// Estimates how volatile packages are, based on their change history.
+pub(crate) trait Volatility {
+    /// Calculate the `change_frequency` passed to ostree-ext for each package, in the order of `packages`.
+    ///
+    /// Like ostree-ext expects, higher values denote more frequent changes. The values are updates per day (or build)
+    /// scaled by [`RATE_SCALE`], so they are comparable across models.
+    fn change_frequencies(&self, packages: &[PackageIndex]) -> Vec<u32>;
+}
+
+/// Updates per day (or build), derived from the average interval between changes, see
+/// [`PackageIndex::average_change_interval`].
+///
+/// Packages without any update since they were first seen count as never changing.
+pub(crate) struct AverageInterval;
+
+impl Volatility for AverageInterval {
+    fn change_frequencies(&self, packages: &[PackageIndex]) -> Vec<u32> {
+        let period = ChangeIdMode::of_index(packages).period() as f64;
+        packages
+            .iter()
+            .map(|pkg| match pkg.average_change_interval() {
+                0 => 0,
+                interval => rate_to_frequency(period / f64::from(interval)),
+            })
+            .collect()
+    }
+}
+
+/// Number of changes, where each change is weighted down exponentially by its age.
+///
+/// Recent updates dominate the estimate, so it follows a package whose update pattern changes. The weighted sum is
+/// normalised such that a package updated with every day (or build) ends up at one update per day.
+pub(crate) struct ExponentialDecay {
+    /// Age (in days or builds) after which a change only counts half
+    pub half_life: u32,
+}
+
+impl Volatility for ExponentialDecay {
+    fn change_frequencies(&self, packages: &[PackageIndex]) -> Vec<u32> {
+        let change_id_mode = ChangeIdMode::of_index(packages);
+        let current_change = change_id_mode.current_change(packages);
+        let half_life = f64::from(self.half_life.max(1));
+        // Inverse of the weighted sum of an infinite history of daily updates
+        let normalisation = 1.0 - 0.5f64.powf(1.0 / half_life);
+        packages
+            .iter()
+            .map(|pkg| {
+                let rate = pkg
+                    .changes
+                    .keys()
+                    .map(|change| {
+                        0.5f64.powf(age(*change, current_change, change_id_mode) / half_life)
+                    })
+                    .sum::<f64>();
+                rate_to_frequency(rate * normalisation)
+            })
+            .collect()
+    }
+}
+
+/// Number of changes within a recent window of days (or builds), divided by the window.
+pub(crate) struct RecentChanges {
+    pub window: u32,
+}
+
+impl Volatility for RecentChanges {
+    fn change_frequencies(&self, packages: &[PackageIndex]) -> Vec<u32> {
+        let change_id_mode = ChangeIdMode::of_index(packages);
+        let current_change = change_id_mode.current_change(packages);
+        packages
+            .iter()
+            .map(|pkg| {
+                let count = pkg
+                    .changes
+                    .keys()
+                    .filter(|change| {
+                        age(**change, current_change, change_id_mode) < f64::from(self.window)
+                    })
+                    .count();
+                rate_to_frequency(count as f64 / f64::from(self.window.max(1)))
+            })
+            .collect()
+    }
+}
+
+/// Bayesian estimate of the update rate per day (or build).
+///
+/// The prior is the combined update rate of all packages sharing the same source, weighted as if it had been
+/// observed for `prior_weight` days (or builds). Packages with a short history thus start out with the rate of
+/// their source group, while packages with a long history are dominated by their own updates.
+pub(crate) struct Bayesian {
+    pub prior_weight: u32,
+}
+
+impl Bayesian {
+    /// Number of updates (changes after the first one) and observed days (or builds) of a package.
+    fn observation(
+        pkg: &PackageIndex,
+        current_change: u64,
+        change_id_mode: ChangeIdMode,
+    ) -> (f64, f64) {
+        let updates = pkg.changes.len().saturating_sub(1) as f64;
+        let span = pkg
+            .changes
+            .keys()
+            .next()
+            .map(|first| age(*first, current_change, change_id_mode))
+            .unwrap_or_default();
+        (updates, span)
+    }
+}
+
+impl Volatility for Bayesian {
+    fn change_frequencies(&self, packages: &[PackageIndex]) -> Vec<u32> {
+        let change_id_mode = ChangeIdMode::of_index(packages);
+        let current_change = change_id_mode.current_change(packages);
+        let observations = packages
+            .iter()
+            .map(|pkg| Self::observation(pkg, current_change, change_id_mode))
+            .collect::<Vec<_>>();
+
+        // Accumulate updates and observed spans per source group, and globally as fallback
+        let mut groups: HashMap<&str, (f64, f64)> = HashMap::new();
+        let mut global = (0.0, 0.0);
+        for (pkg, (updates, span)) in packages.iter().zip(&observations) {
+            let group = groups.entry(pkg.package.source.as_str()).or_default();
+            group.0 += updates;
+            group.1 += span;
+            global.0 += updates;
+            global.1 += span;
+        }
+        let rate = |(updates, span): (f64, f64)| {
+            if span > 0.0 {
+                Some(updates / span)
+            } else {
+                None
+            }
+        };
+        let global_rate = rate(global).unwrap_or_default();
+
+        let prior_weight = f64::from(self.prior_weight);
+        packages
+            .iter()
+            .zip(observations)
+            .map(|(pkg, (updates, span))| {
+                let prior = rate(groups[pkg.package.source.as_str()]).unwrap_or(global_rate);
+                rate_to_frequency((prior_weight * prior + updates) / (prior_weight + span).max(1.0))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pkgdb::{ChangeFrequencyClass, Package};
+
+    use super::*;
+
+    fn index(name: &str, source: &str, changes: &[u64]) -> PackageIndex {
+        let package = Package {
+            identifier: name.to_string(),
+            name: name.to_string(),
+            source: source.to_string(),
+            ..Default::default()
+        };
+        PackageIndex::new(package, changes.iter().copied())
+            .with_change_id_mode(ChangeIdMode::BuildNumber)
+    }
+
+    #[test]
+    fn test_average_interval() {
+        let packages = [
+            index("daily", "daily", &[1, 2, 3, 4]),
+            index("weekly", "weekly", &[1, 8, 15]),
+            index("unchanged", "unchanged", &[1]),
+        ];
+        assert_eq!(
+            AverageInterval.change_frequencies(&packages),
+            [1000, 143, 0]
+        );
+    }
+
+    #[test]
+    fn test_exponential_decay_prefers_recent_changes() {
+        let packages = [
+            index("recent", "recent", &[9, 10]),
+            index("old", "old", &[1, 2]),
+        ];
+        let frequencies = ExponentialDecay { half_life: 2 }.change_frequencies(&packages);
+        assert_eq!(frequencies[0], 500);
+        assert!(frequencies[0] > frequencies[1]);
+    }
+
+    #[test]
+    fn test_models_share_scale() {
+        let daily = (1..=200).collect::<Vec<_>>();
+        let packages = [
+            index("daily", "daily", &daily),
+            index("rare", "rare", &[1, 200]),
+        ];
+        let models: [Box<dyn Volatility>; 4] = [
+            Box::new(AverageInterval),
+            Box::new(ExponentialDecay { half_life: 30 }),
+            Box::new(RecentChanges { window: 30 }),
+            Box::new(Bayesian { prior_weight: 30 }),
+        ];
+        for model in models {
+            let frequencies = model.change_frequencies(&packages);
+            assert!((990..=1000).contains(&frequencies[0]), "{frequencies:?}");
+            assert!(ChangeFrequencyClass::Never.change_frequency() < frequencies[1]);
+            assert!(frequencies[1] < frequencies[0]);
+            assert!(frequencies[0] < ChangeFrequencyClass::Always.change_frequency());
+        }
+    }
+
+    #[test]
+    fn test_recent_changes() {
+        let packages = [index("a", "a", &[1, 5, 8, 9, 10])];
+        assert_eq!(
+            RecentChanges { window: 3 }.change_frequencies(&packages),
+            [1000]
+        );
+    }
+
+    #[test]
+    fn test_bayesian_uses_source_group_prior() {
+        let packages = [
+            index("a", "group", &[0, 2, 4, 6, 8, 10]),
+            // Newly added package with no updates yet
+            index("b", "group", &[10]),
+            index("c", "other", &[0, 10]),
+            index("d", "other", &[10]),
+        ];
+        let frequencies = Bayesian { prior_weight: 10 }.change_frequencies(&packages);
+        assert!(frequencies[1] > frequencies[3]);
+        assert_eq!(frequencies[1], 500);
+    }
+}
@@ -6,13 +6,18 @@ mod util;
 use clap::{Parser, Subcommand};
 use rpm_ostree::*;
 
-use crate::{chunking::cli::GenerateChunkedOCIOpts, pkgdb::cli::BuildPackageIndexOpts};
+use crate::{
+    chunking::cli::GenerateChunkedOCIOpts,
+    pkgdb::cli::{BuildPackageIndexOpts, CheckPostprocessingOpts},
+};
 
 #[derive(Debug, Subcommand)]
 enum Subcommands {
     GenerateOstreeRepo(BuildChunkedOCIOpts),
     BuildPackageIndex(BuildPackageIndexOpts),
+    CheckPostprocessing(CheckPostprocessingOpts),
     GenerateChunkedOCI(GenerateChunkedOCIOpts),
+    GenerateStaticDelta(GenerateStaticDeltaOpts),
 }
 
 impl Subcommands {
@@ -24,9 +29,15 @@ impl Subcommands {
             Subcommands::BuildPackageIndex(build_package_index_opts) => {
                 build_package_index_opts.run()
             }
+            Subcommands::CheckPostprocessing(check_postprocessing_opts) => {
+                check_postprocessing_opts.run()
+            }
             Subcommands::GenerateChunkedOCI(generate_chunked_ociopts) => {
                 generate_chunked_ociopts.run()
             }
+            Subcommands::GenerateStaticDelta(generate_static_delta_opts) => {
+                generate_static_delta_opts.run()
+            }
         }
     }
 }
@@ -45,6 +45,17 @@ impl PackageDatabase for AlpmDb {
                     .iter()
                     .map(|f| Utf8PathBuf::from_str(&format!("/{}", f.name())).unwrap())
                     .collect(),
+                replaces: pkg
+                    .replaces()
+                    .iter()
+                    .map(|dep| dep.name().to_string())
+                    .collect(),
+                provides: pkg
+                    .provides()
+                    .iter()
+                    .map(|dep| dep.name().to_string())
+                    .collect(),
+                ..Default::default()
             })
             .collect())
     }
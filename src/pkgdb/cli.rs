@@ -8,8 +8,9 @@ use clap::{Args, ValueEnum};
 use crate::pkgdb::archlinux::AlpmDb;
 use crate::{
     pkgdb::{
-        PackageDatabase, PackageDatabaseWithDefaultPath, PackageIndex,
-        postprocessing::Postprocessing, rpm::RpmDb,
+        ChangeIdMode, PackageDatabase, PackageDatabaseWithDefaultPath, PackageIndex,
+        postprocessing::{Postprocessing, PostprocessingPreset, Severity, check_diagnostics},
+        rpm::RpmDb,
     },
     rpm_ostree::run_with_mount,
     util::get_buildtime,
@@ -145,6 +146,19 @@ pub(crate) struct BuildPackageIndexOpts {
     pub changelog_source: ChangelogSource,
     #[clap(long, required = false, default_value = "weekly")]
     pub changelog_resolution: ChangelogResolution,
+    #[clap(
+        long,
+        required = false,
+        default_value = "timestamp",
+        help = "derive change IDs from the (SOURCE_DATE_EPOCH or current) build time or from a build counter"
+    )]
+    pub change_id_mode: ChangeIdMode,
+    #[clap(
+        long,
+        required = false,
+        help = "build number to use as change ID in build-number mode (defaults to the previous one incremented by one)"
+    )]
+    pub build_number: Option<u64>,
     #[clap(long, required = false)]
     pub previous_package_index: Option<Utf8PathBuf>,
     #[clap(long, required = false)]
@@ -152,11 +166,30 @@ pub(crate) struct BuildPackageIndexOpts {
     #[clap(
         long,
         required = false,
-        help = "TOML file with postprocessing information (add and merge packages)"
+        help = "TOML file with postprocessing information (add and merge packages), layered on top of the preset if given"
     )]
     pub postprocessing: Option<Utf8PathBuf>,
+    #[clap(
+        long,
+        required = false,
+        help = "built-in postprocessing configuration for the distribution"
+    )]
+    pub postprocessing_preset: Option<PostprocessingPreset>,
+    #[clap(
+        long,
+        required = false,
+        help = "fail on warnings from validating the postprocessing configuration, not only on errors"
+    )]
+    pub strict_postprocessing: bool,
     #[clap(long, required = false)]
     pub output_ostree_ext_metadata: Option<Utf8PathBuf>,
+    #[clap(
+        long,
+        required = false,
+        default_value_t = 10,
+        help = "number of index generations to keep the history of removed packages for (0 to discard it immediately)"
+    )]
+    pub tombstone_retention: u32,
 }
 
 impl BuildPackageIndexOpts {
@@ -168,20 +201,81 @@ impl BuildPackageIndexOpts {
         )
     }
 
+    /// Load the previous package index, if the changelog is to be obtained from it.
+    fn load_previous_package_index(&self) -> Result<Option<Vec<PackageIndex>>, anyhow::Error> {
+        if self.changelog_source != ChangelogSource::PreviousIndex {
+            return Ok(None);
+        }
+        let Some(previous_package_index) = &self.previous_package_index else {
+            anyhow::bail!(
+                "Obtaining changelog from previous index file requested, but no previous index file was specified"
+            );
+        };
+        let previous_package_index =
+            serde_json::from_reader::<_, Vec<PackageIndex>>(File::open(previous_package_index)?)?;
+        if let Some(package) = previous_package_index
+            .iter()
+            .find(|package| package.change_id_mode != self.change_id_mode)
+        {
+            anyhow::bail!(
+                "Previous package index uses change ID mode {:?} (e.g. for {}), but {:?} was requested",
+                package.change_id_mode,
+                package.package.name,
+                self.change_id_mode
+            );
+        }
+        Ok(Some(previous_package_index))
+    }
+
+    /// Determine the change ID of the current build.
+    fn current_change_id(
+        &self,
+        previous_package_index: Option<&[PackageIndex]>,
+    ) -> Result<u64, anyhow::Error> {
+        match self.change_id_mode {
+            // We use the current build time as an ID for the changelog, if it is not populated from the package database.
+            ChangeIdMode::Timestamp => self.changelog_resolution.normalize(get_buildtime()),
+            ChangeIdMode::BuildNumber => {
+                if self.changelog_source == ChangelogSource::PackageDatabase {
+                    anyhow::bail!(
+                        "The package database only provides timestamps, which cannot be used in build-number mode"
+                    );
+                }
+                let last_build_number = previous_package_index.and_then(ChangeIdMode::last_build);
+                match (self.build_number, last_build_number) {
+                    (Some(build_number), Some(last_build_number))
+                        if build_number <= last_build_number =>
+                    {
+                        anyhow::bail!(
+                            "Build number {} is not larger than the last recorded build number {}",
+                            build_number,
+                            last_build_number
+                        )
+                    }
+                    (Some(build_number), _) => Ok(build_number),
+                    (None, last_build_number) => Ok(last_build_number.map_or(1, |last| last + 1)),
+                }
+            }
+        }
+    }
+
     fn run_with_sysroot(&self, sysroot: &Utf8Path) -> Result<(), anyhow::Error> {
         tracing::trace!("Running with sysroot {:?}", sysroot);
-        // We use the current build time as an ID for the changelog, if it is not populated from the package database.
-        let build_time = get_buildtime();
-        let change_id = self.changelog_resolution.normalize(build_time)?;
+        let previous_package_index = self.load_previous_package_index()?;
+        let change_id = self.current_change_id(previous_package_index.as_deref())?;
+        tracing::debug!("Using change ID {} ({:?})", change_id, self.change_id_mode);
         let backend = self
             .backend
             .get_backend(sysroot, self.pkgdb_path.as_ref().map(|p| p.as_ref()))?;
         let packages = backend.get_packages()?;
         tracing::debug!("Obtained {} packages from database", packages.len());
-        let packages = match self.postprocessing {
-            Some(ref postprocessing_path) => {
-                let postprocessing = Postprocessing::new_from_toml(postprocessing_path)?;
-                postprocessing.apply(packages)?
+        let postprocessing =
+            Postprocessing::load(self.postprocessing_preset, self.postprocessing.as_deref())?;
+        let packages = match postprocessing {
+            Some(postprocessing) => {
+                let (diagnostics, packages) = postprocessing.apply_and_validate(packages, sysroot);
+                check_diagnostics(&diagnostics, self.strict_postprocessing)?;
+                packages?
             }
             None => packages,
         };
@@ -206,39 +300,102 @@ impl BuildPackageIndexOpts {
                 })
                 .collect::<Result<Vec<PackageIndex>, anyhow::Error>>()?,
             ChangelogSource::PreviousIndex => {
-                let previous_package_metadata = if let Some(previous_package_metadata) =
-                    &self.previous_package_index
-                {
-                    serde_json::from_reader::<_, Vec<PackageIndex>>(File::open(
-                        previous_package_metadata,
-                    )?)?
-                } else {
-                    anyhow::bail!(
-                        "Obtaining changelog from previous index file requested, but no previous index file was specified"
-                    );
-                };
-                let mut previous_package_metadata = previous_package_metadata
+                // Safety: The previous index is always loaded for this changelog source
+                let mut previous_package_metadata = previous_package_index
+                    .unwrap()
                     .into_iter()
                     .map(|package| (package.package.name.clone(), package))
                     .collect::<HashMap<String, PackageIndex>>();
-                packages
+                // Packages split off from another package start out with the history of their parent
+                let split_parents = packages
+                    .iter()
+                    .filter_map(|package| package.split_from.as_ref())
+                    .filter_map(|parent| {
+                        previous_package_metadata
+                            .get(parent)
+                            .map(|metadata| (parent.clone(), metadata.clone()))
+                    })
+                    .collect::<HashMap<String, PackageIndex>>();
+                let mut packages = packages
                     .into_iter()
                     .map(|package| {
                         let previous_version = previous_package_metadata.remove(&package.name);
-                        match previous_version {
-                            Some(metadata) => PackageIndex::update_from_previous_index(
-                                package, metadata, change_id,
-                            ),
-                            None => PackageIndex::initialize(package, change_id),
+                        (package, previous_version)
+                    })
+                    .collect::<Vec<_>>();
+                // Packages that did not exist under their current name may have been renamed or replaced.
+                // This is done in a second pass, so packages that still exist keep their own history.
+                for (package, previous_version) in packages
+                    .iter_mut()
+                    .filter(|(_package, previous_version)| previous_version.is_none())
+                {
+                    // Provides are not considered, as unrelated packages may share virtual provides
+                    *previous_version = package
+                        .replaces
+                        .iter()
+                        .find_map(|name| previous_package_metadata.remove(name))
+                        .or_else(|| {
+                            package
+                                .split_from
+                                .as_ref()
+                                .and_then(|parent| split_parents.get(parent).cloned())
+                        });
+                    if let Some(previous_version) = previous_version {
+                        tracing::debug!(
+                            "Transferring history of {} to {}",
+                            previous_version.package.name,
+                            package.name
+                        );
+                    }
+                }
+                let mut packages = packages
+                    .into_iter()
+                    .map(|(package, previous_version)| match previous_version {
+                        Some(metadata) => {
+                            if let Some(tombstone) = &metadata.removed {
+                                tracing::debug!(
+                                    "Restoring history of {}, which was removed {} generation(s) ago",
+                                    package.name,
+                                    tombstone.generations
+                                );
+                            }
+                            PackageIndex::update_from_previous_index(package, metadata, change_id)
                         }
+                        None => PackageIndex::initialize(package, change_id),
                     })
-                    .collect()
+                    .collect::<Vec<PackageIndex>>();
+                // Whatever is left over was removed from the image. Keep it as a tombstone for a while, so its
+                // history can be restored if the package is reintroduced.
+                let mut tombstones = previous_package_metadata
+                    .into_values()
+                    .map(|package| package.into_tombstone(change_id))
+                    .filter(|package| {
+                        package.removed.as_ref().is_some_and(|tombstone| {
+                            tombstone.generations <= self.tombstone_retention
+                        })
+                    })
+                    .collect::<Vec<PackageIndex>>();
+                tracing::debug!(
+                    "Keeping {} removed packages as tombstones",
+                    tombstones.len()
+                );
+                tombstones.sort_by(|a, b| a.package.name.cmp(&b.package.name));
+                packages.extend(tombstones);
+                packages
             }
             ChangelogSource::Initialize => packages
                 .into_iter()
                 .map(|package| PackageIndex::initialize(package, change_id))
                 .collect(),
         };
+        let packages = packages
+            .into_iter()
+            .map(|package| {
+                package
+                    .with_change_id_mode(self.change_id_mode)
+                    .with_last_build(change_id)
+            })
+            .collect::<Vec<PackageIndex>>();
         tracing::trace!("Changelog created");
         if let Some(output_package_index) = &self.output_package_index {
             serde_json::to_writer(File::create_new(output_package_index)?, &packages)?;
@@ -247,3 +404,76 @@ impl BuildPackageIndexOpts {
         Ok(())
     }
 }
+
+/// Validate a postprocessing configuration against the packages of a rootfs or container image.
+#[derive(Args, Debug)]
+pub(crate) struct CheckPostprocessingOpts {
+    #[clap(long, required = false, default_value = "rpm")]
+    pub backend: PackageBackend,
+    #[clap(
+        long,
+        required_unless_present = "image",
+        help = "path to a rootfs containing the package manager database"
+    )]
+    pub sysroot: Option<Utf8PathBuf>,
+    #[clap(
+        long,
+        required_unless_present = "sysroot",
+        help = "path to a container image in container-storage containing the package manager database"
+    )]
+    pub image: Option<String>,
+    #[clap(
+        long,
+        required = false,
+        help = "path to the package manager database inside the image/rootfs"
+    )]
+    pub pkgdb_path: Option<Utf8PathBuf>,
+    #[clap(
+        long,
+        required_unless_present = "postprocessing_preset",
+        help = "TOML file with postprocessing information, layered on top of the preset if given"
+    )]
+    pub postprocessing: Option<Utf8PathBuf>,
+    #[clap(
+        long,
+        required = false,
+        help = "built-in postprocessing configuration for the distribution"
+    )]
+    pub postprocessing_preset: Option<PostprocessingPreset>,
+    #[clap(long, required = false, help = "treat warnings as errors")]
+    pub strict: bool,
+}
+
+impl CheckPostprocessingOpts {
+    pub(crate) fn run(&self) -> Result<(), anyhow::Error> {
+        run_with_mount(
+            |dir| self.run_with_sysroot(dir),
+            self.sysroot.clone(),
+            self.image.clone(),
+        )
+    }
+
+    fn run_with_sysroot(&self, sysroot: &Utf8Path) -> Result<(), anyhow::Error> {
+        // Safety: Either a file or a preset is required
+        let postprocessing =
+            Postprocessing::load(self.postprocessing_preset, self.postprocessing.as_deref())?
+                .unwrap();
+        let backend = self
+            .backend
+            .get_backend(sysroot, self.pkgdb_path.as_ref().map(|p| p.as_ref()))?;
+        let packages = backend.get_packages()?;
+        let package_count = packages.len();
+        let (diagnostics, _packages) = postprocessing.apply_and_validate(packages, sysroot);
+        // This logs each diagnostic, so they are not printed again
+        check_diagnostics(&diagnostics, self.strict)?;
+        let warnings = diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == Severity::Warning)
+            .count();
+        println!(
+            "Postprocessing configuration is valid for {} packages ({} warning(s))",
+            package_count, warnings
+        );
+        Ok(())
+    }
+}
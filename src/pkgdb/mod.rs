@@ -1,19 +1,21 @@
 use std::{
-    collections::{BTreeSet, HashSet},
+    collections::{BTreeMap, BTreeSet, HashSet},
     hash::Hash,
     ops::Div,
-    rc::Rc,
 };
 
 use camino::Utf8PathBuf;
-use ostree_ext::{chunking::ObjectSourceMetaSized, objectsource::ObjectSourceMeta};
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
 
+use crate::util::get_buildtime;
+
 #[cfg(feature = "archlinux")]
 pub(crate) mod archlinux;
 pub(crate) mod rpm;
 
 pub(crate) mod cli;
+pub(crate) mod pattern;
 pub(crate) mod postprocessing;
 
 pub(crate) const MAXIMUM_CHANGES: usize = 100;
@@ -37,6 +39,59 @@ pub struct Package {
 
     // List of files
     pub files: HashSet<Utf8PathBuf>,
+
+    // Names of packages this package replaces or obsoletes, including names it was renamed from
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub replaces: BTreeSet<String>,
+
+    // Names of (virtual) packages this package provides
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub provides: BTreeSet<String>,
+
+    // Name of the package this package was split off from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub split_from: Option<String>,
+
+    // Metadata forced by postprocessing
+    #[serde(default, skip_serializing_if = "MetadataOverrides::is_empty")]
+    pub overrides: MetadataOverrides,
+}
+
+/// Coarse change frequency classes, for packages whose volatility is known upfront.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChangeFrequencyClass {
+    /// The package (practically) never changes
+    Never,
+    /// The package changes with every build, like the unpackaged content
+    Always,
+}
+
+impl ChangeFrequencyClass {
+    /// The `change_frequency` handed to ostree-ext, the extremes of the scale shared by all volatility models
+    pub fn change_frequency(&self) -> u32 {
+        match self {
+            ChangeFrequencyClass::Never => 0,
+            ChangeFrequencyClass::Always => u32::MAX,
+        }
+    }
+}
+
+/// Metadata that takes precedence over what is derived from the change history of a package.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetadataOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change_frequency: Option<ChangeFrequencyClass>,
+
+    // Offset to the earliest changed package in hours (or builds)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change_time_offset: Option<u32>,
+}
+
+impl MetadataOverrides {
+    pub fn is_empty(&self) -> bool {
+        self.change_frequency.is_none() && self.change_time_offset.is_none()
+    }
 }
 
 impl Hash for Package {
@@ -45,50 +100,247 @@ impl Hash for Package {
     }
 }
 
+/// Details about a single package change. The change ID itself is the key in [`PackageIndex::changes`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    // Package version the package changed to, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+
+    // Package identifier the package changed to, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identifier: Option<String>,
+}
+
+impl From<&Package> for Change {
+    fn from(value: &Package) -> Self {
+        Self {
+            version: Some(value.version.clone()),
+            identifier: Some(value.identifier.clone()),
+        }
+    }
+}
+
+/// (De-)serialize the changes of a [`PackageIndex`] as a list of change entries.
+///
+/// Older package indices stored a plain list of change IDs, which are still accepted on deserialization.
+mod changes_serde {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::Change;
+
+    #[derive(Serialize)]
+    struct ChangeEntryRef<'a> {
+        id: u64,
+        #[serde(flatten)]
+        change: &'a Change,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ChangeEntry {
+        Legacy(u64),
+        Full {
+            id: u64,
+            #[serde(flatten)]
+            change: Change,
+        },
+    }
+
+    pub(super) fn serialize<S: Serializer>(
+        changes: &BTreeMap<u64, Change>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            changes
+                .iter()
+                .map(|(id, change)| ChangeEntryRef { id: *id, change }),
+        )
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<u64, Change>, D::Error> {
+        Ok(Vec::<ChangeEntry>::deserialize(deserializer)?
+            .into_iter()
+            .map(|entry| match entry {
+                ChangeEntry::Legacy(id) => (id, Change::default()),
+                ChangeEntry::Full { id, change } => (id, change),
+            })
+            .collect())
+    }
+}
+
+/// What the change IDs of a [`PackageIndex`] are derived from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChangeIdMode {
+    /// Unix timestamps of the builds (or package changes), normalized by the changelog resolution
+    #[default]
+    Timestamp,
+    /// A monotonically increasing build counter
+    BuildNumber,
+}
+
+impl ChangeIdMode {
+    /// Length of a day in change ID units. Build numbers have no notion of time, so one build counts as one day.
+    pub fn period(&self) -> u64 {
+        match self {
+            ChangeIdMode::Timestamp => 24 * 60 * 60,
+            ChangeIdMode::BuildNumber => 1,
+        }
+    }
+
+    /// The change ID of the current build, as far as it can be derived from a package index.
+    pub fn current_change(&self, packages: &[PackageIndex]) -> u64 {
+        match self {
+            ChangeIdMode::Timestamp => get_buildtime(),
+            ChangeIdMode::BuildNumber => Self::last_build(packages).unwrap_or_default(),
+        }
+    }
+
+    /// The change ID of the build a package index was generated in.
+    ///
+    /// Indices written before the build was recorded fall back to the most recent package change.
+    pub fn last_build(packages: &[PackageIndex]) -> Option<u64> {
+        packages
+            .iter()
+            .filter_map(|pkg| pkg.last_build.or_else(|| pkg.last_change()))
+            .max()
+    }
+
+    /// The change ID mode of a package index. All packages of an index share the same mode.
+    pub fn of_index(packages: &[PackageIndex]) -> Self {
+        packages
+            .first()
+            .map(|pkg| pkg.change_id_mode)
+            .unwrap_or_default()
+    }
+}
+
+/// Marks a package that is no longer part of the image, but whose history is retained.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tombstone {
+    // Change ID of the build the package was removed in
+    pub change: u64,
+
+    // Number of index generations the package has been missing from the image
+    pub generations: u32,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PackageIndex {
     pub package: Package,
-    pub changes: BTreeSet<u64>,
+    #[serde(with = "changes_serde")]
+    pub changes: BTreeMap<u64, Change>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub removed: Option<Tombstone>,
+    #[serde(default)]
+    pub change_id_mode: ChangeIdMode,
+    // Change ID of the build the index was generated in, as builds without package changes leave no trace in
+    // the changes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_build: Option<u64>,
 }
 
 impl PackageIndex {
+    /// Carry over the history of `previous_index` to `package`.
+    ///
+    /// The previous index usually belongs to a package of the same name, but it may also stem from a
+    /// package that `package` obsoletes or was renamed from, so the history survives renames.
     pub fn update_from_previous_index(
         package: Package,
         previous_index: PackageIndex,
         current_change: u64,
     ) -> Self {
-        assert_eq!(package.name, previous_index.package.name);
         let mut changes = previous_index.changes;
+        // A package that comes back after its removal changes the image, even if its version did not change.
         let is_new_version = package.version != previous_index.package.version
-            || package.identifier != previous_index.package.identifier;
+            || package.identifier != previous_index.package.identifier
+            || previous_index.removed.is_some();
         let is_new_build = changes
-            .last()
-            .map(|last| *last != current_change)
+            .last_key_value()
+            .map(|(last, _)| *last != current_change)
             .unwrap_or(true);
         if is_new_version && is_new_build {
-            changes.insert(current_change);
+            changes.insert(current_change, Change::from(&package));
         }
         while changes.len() > MAXIMUM_CHANGES {
             let _ = changes.pop_first();
         }
-        Self { package, changes }
+        Self {
+            package,
+            changes,
+            removed: None,
+            change_id_mode: previous_index.change_id_mode,
+            last_build: previous_index.last_build,
+        }
+    }
+
+    /// Keep the history of a package that is no longer part of the image.
+    ///
+    /// Packages that are already tombstones age by one generation.
+    pub fn into_tombstone(mut self, current_change: u64) -> Self {
+        match self.removed.as_mut() {
+            Some(tombstone) => tombstone.generations += 1,
+            None => {
+                self.removed = Some(Tombstone {
+                    change: current_change,
+                    generations: 1,
+                })
+            }
+        }
+        self
+    }
+
+    /// Whether the package was removed from the image and only its history is retained.
+    pub fn is_removed(&self) -> bool {
+        self.removed.is_some()
     }
 
     pub fn initialize(package: Package, current_change: u64) -> Self {
+        let change = Change::from(&package);
         Self {
             package,
-            changes: BTreeSet::from_iter([current_change]),
+            changes: BTreeMap::from_iter([(current_change, change)]),
+            removed: None,
+            change_id_mode: ChangeIdMode::default(),
+            last_build: None,
         }
     }
 
     pub fn new<I: IntoIterator<Item = u64>>(package: Package, changes: I) -> Self {
         Self {
             package,
-            changes: BTreeSet::from_iter(changes),
+            changes: changes
+                .into_iter()
+                .map(|change| (change, Change::default()))
+                .collect(),
+            removed: None,
+            change_id_mode: ChangeIdMode::default(),
+            last_build: None,
         }
     }
 
-    pub fn change_frequency(&self) -> u32 {
+    pub fn with_change_id_mode(mut self, change_id_mode: ChangeIdMode) -> Self {
+        self.change_id_mode = change_id_mode;
+        self
+    }
+
+    pub fn with_last_build(mut self, last_build: u64) -> Self {
+        self.last_build = Some(last_build);
+        self
+    }
+
+    /// The ID of the most recent change, if there is any.
+    pub fn last_change(&self) -> Option<u64> {
+        self.changes.last_key_value().map(|(id, _)| *id)
+    }
+
+    /// The average interval between changes, in the unit of the change IDs (seconds or builds).
+    pub fn average_change_interval(&self) -> u32 {
         if self.changes.len() < 2 {
             return 0;
         }
@@ -96,7 +348,7 @@ impl PackageIndex {
         // Sum over the time differences of changes
         let diff = self
             .changes
-            .iter()
+            .keys()
             .fold((0u64, None), |(sum, last_element), current| {
                 if let Some(last) = last_element {
                     (sum + (current - last), Some(current))
@@ -127,17 +379,95 @@ pub trait PackageDatabaseWithDefaultPath: PackageDatabase {
     const DEFAULT_PATH: &'static str;
 }
 
-impl From<Package> for ObjectSourceMetaSized {
-    fn from(value: Package) -> Self {
-        ObjectSourceMetaSized {
-            meta: ObjectSourceMeta {
-                identifier: Rc::from(value.identifier),
-                name: Rc::from(value.name),
-                srcid: Rc::from(value.source),
-                change_time_offset: 1,
-                change_frequency: 0,
-            },
-            size: value.size,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_legacy_changes() {
+        let index: PackageIndex = serde_json::from_str(
+            r#"{"package":{"identifier":"a-1","name":"a","version":"1","source":"a","size":0,"files":[]},"changes":[1,2]}"#,
+        )
+        .unwrap();
+        assert_eq!(index.changes.len(), 2);
+        assert_eq!(index.changes[&2], Change::default());
+    }
+
+    #[test]
+    fn test_changes_roundtrip() {
+        let package = Package {
+            identifier: "a-2".to_string(),
+            name: "a".to_string(),
+            version: "2".to_string(),
+            ..Default::default()
+        };
+        let index = PackageIndex::initialize(package, 42);
+        let index: PackageIndex =
+            serde_json::from_str(&serde_json::to_string(&index).unwrap()).unwrap();
+        assert_eq!(index.last_change(), Some(42));
+        assert_eq!(index.changes[&42].version.as_deref(), Some("2"));
+        assert_eq!(index.changes[&42].identifier.as_deref(), Some("a-2"));
+    }
+
+    #[test]
+    fn test_tombstone_reintroduction() {
+        let package = Package {
+            identifier: "a-1".to_string(),
+            name: "a".to_string(),
+            version: "1".to_string(),
+            ..Default::default()
+        };
+        let index = PackageIndex::initialize(package.clone(), 1)
+            .into_tombstone(2)
+            .into_tombstone(3);
+        assert_eq!(
+            index.removed,
+            Some(Tombstone {
+                change: 2,
+                generations: 2
+            })
+        );
+        let index = PackageIndex::update_from_previous_index(package, index, 4);
+        assert!(!index.is_removed());
+        assert_eq!(index.changes.keys().copied().collect::<Vec<_>>(), [1, 4]);
+    }
+
+    #[test]
+    fn test_build_numbers_without_changes() {
+        let package = Package {
+            identifier: "a-1".to_string(),
+            name: "a".to_string(),
+            version: "1".to_string(),
+            ..Default::default()
+        };
+        let mode = ChangeIdMode::BuildNumber;
+        let mut index = vec![
+            PackageIndex::initialize(package.clone(), 1)
+                .with_change_id_mode(mode)
+                .with_last_build(1),
+        ];
+        let mut builds = vec![];
+        for _ in 0..2 {
+            let build = ChangeIdMode::last_build(&index).unwrap() + 1;
+            index = index
+                .into_iter()
+                .map(|previous| {
+                    PackageIndex::update_from_previous_index(package.clone(), previous, build)
+                        .with_last_build(build)
+                })
+                .collect();
+            assert_eq!(mode.current_change(&index), build);
+            builds.push(build);
         }
+        assert_eq!(builds, [2, 3]);
+        // The package did not change, so only the initial build is part of its history
+        assert_eq!(index[0].changes.keys().copied().collect::<Vec<_>>(), [1]);
+
+        // Indices without the build fall back to the most recent change
+        let legacy: PackageIndex = serde_json::from_str(
+            r#"{"package":{"identifier":"a-1","name":"a","version":"1","source":"a","size":0,"files":[]},"changes":[1,5],"change_id_mode":"build-number"}"#,
+        )
+        .unwrap();
+        assert_eq!(ChangeIdMode::last_build(&[legacy]), Some(5));
     }
 }
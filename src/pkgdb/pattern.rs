@@ -0,0 +1,140 @@
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+
+/// Prefix marking a pattern as regular expression
+const REGEX_PREFIX: &str = "re:";
+
+/// Translate a glob into an anchored regular expression.
+///
+/// `*` and `?` do not match `/`, while `**` matches across path components. Character classes like `[a-z]` and
+/// `[!a-z]` are supported as well.
+pub fn glob_to_regex(glob: &str) -> Result<Regex, anyhow::Error> {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                let _ = chars.next();
+                // `/**/` also matches a single `/`
+                if regex.ends_with('/') && chars.peek() == Some(&'/') {
+                    let _ = chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if chars.peek() == Some(&'!') {
+                    let _ = chars.next();
+                    regex.push('^');
+                }
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some('\\') => regex.push_str("\\\\"),
+                        Some(c) => regex.push(c),
+                        None => anyhow::bail!("Unterminated character class in glob {:?}", glob),
+                    }
+                }
+                regex.push(']');
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Ok(Regex::new(&regex)?)
+}
+
+/// A pattern matching package names.
+///
+/// Patterns starting with `re:` are regular expressions, patterns containing `*`, `?` or `[` are globs and everything
+/// else matches a name exactly.
+#[derive(Clone, Debug)]
+pub(crate) enum Pattern {
+    Exact(String),
+    Glob(String, Regex),
+    Regex(String, Regex),
+}
+
+impl Pattern {
+    pub(crate) fn new(pattern: &str) -> Result<Self, anyhow::Error> {
+        if let Some(regex) = pattern.strip_prefix(REGEX_PREFIX) {
+            // Regular expressions always have to match the whole name
+            Ok(Pattern::Regex(
+                pattern.to_string(),
+                Regex::new(&format!("^(?:{})$", regex))?,
+            ))
+        } else if pattern.contains(['*', '?', '[']) {
+            Ok(Pattern::Glob(pattern.to_string(), glob_to_regex(pattern)?))
+        } else {
+            Ok(Pattern::Exact(pattern.to_string()))
+        }
+    }
+
+    pub(crate) fn matches(&self, name: &str) -> bool {
+        match self {
+            Pattern::Exact(exact) => exact == name,
+            Pattern::Glob(_, regex) | Pattern::Regex(_, regex) => regex.is_match(name),
+        }
+    }
+
+    pub(crate) fn is_exact(&self) -> bool {
+        matches!(self, Pattern::Exact(_))
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        match self {
+            Pattern::Exact(pattern) | Pattern::Glob(pattern, _) | Pattern::Regex(pattern, _) => {
+                pattern
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Pattern::new(&pattern).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patterns() {
+        let exact = Pattern::new("python").unwrap();
+        assert!(exact.is_exact());
+        assert!(exact.matches("python"));
+        assert!(!exact.matches("python-pip"));
+
+        let glob = Pattern::new("python-*").unwrap();
+        assert!(glob.matches("python-pip"));
+        assert!(!glob.matches("python"));
+        assert!(!glob.matches("mypython-pip"));
+
+        let regex = Pattern::new("re:lib(x|xcb)-.*").unwrap();
+        assert!(regex.matches("libxcb-util"));
+        assert!(!regex.matches("mylibxcb-util"));
+        assert!(!regex.matches("libfoo-libx-bar"));
+    }
+
+    #[test]
+    fn test_path_globs() {
+        let regex = glob_to_regex("/usr/lib/modules/*/initramfs.img").unwrap();
+        assert!(regex.is_match("/usr/lib/modules/6.15.9-arch1-1/initramfs.img"));
+        assert!(!regex.is_match("/usr/lib/modules/6.15.9-arch1-1/extra/initramfs.img"));
+
+        let regex = glob_to_regex("/usr/share/flatpak/**").unwrap();
+        assert!(regex.is_match("/usr/share/flatpak/repo/config"));
+
+        let regex = glob_to_regex("/usr/**/*.py[!c]").unwrap();
+        assert!(regex.is_match("/usr/foo.pyx"));
+        assert!(regex.is_match("/usr/lib/python/foo.pyi"));
+        assert!(!regex.is_match("/usr/lib/python/foo.pyc"));
+    }
+}
@@ -1,92 +1,1007 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use regex::Regex;
 use serde::Deserialize;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fs::File,
     io::Read,
     path::Path,
 };
 
-use crate::pkgdb::Package;
+use crate::pkgdb::{
+    ChangeFrequencyClass, Package,
+    pattern::{Pattern, glob_to_regex},
+};
 
 pub fn extend_string_with_separator(original: &mut String, extend_with: &str, separator: char) {
-    if original.len() == 0 {
+    if original.is_empty() {
         original.push_str(extend_with);
     } else {
         original.push_str(&format!("{}{}", separator, extend_with));
     }
 }
 
+/// Size of a file in the sysroot. Anything but an existing regular file counts as empty.
+fn file_size(sysroot: &Utf8Path, path: &Utf8Path) -> u64 {
+    sysroot
+        .join(path.strip_prefix("/").unwrap_or(path))
+        .symlink_metadata()
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .unwrap_or_default()
+}
+
+/// Remove the files matching `predicate` from a package and reduce its size accordingly.
+///
+/// Returns the number of removed files.
+fn remove_files<F: Fn(&Utf8PathBuf) -> bool>(
+    package: &mut Package,
+    sysroot: &Utf8Path,
+    predicate: F,
+) -> usize {
+    let count = package.files.len();
+    package.files.retain(|file| {
+        if predicate(file) {
+            package.size = package.size.saturating_sub(file_size(sysroot, file));
+            false
+        } else {
+            true
+        }
+    });
+    count - package.files.len()
+}
+
+/// Recursively collect all non-directory entries below `dir` matching `regex`.
+fn walk_matching(
+    sysroot: &Utf8Path,
+    dir: &Utf8Path,
+    regex: &Regex,
+    matches: &mut BTreeSet<Utf8PathBuf>,
+) -> Result<(), anyhow::Error> {
+    for entry in sysroot
+        .join(dir.strip_prefix("/").unwrap())
+        .read_dir_utf8()?
+    {
+        let entry = entry?;
+        let path = dir.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            walk_matching(sysroot, &path, regex, matches)?;
+        } else if regex.is_match(path.as_str()) {
+            matches.insert(path);
+        }
+    }
+    Ok(())
+}
+
+/// Resolve an absolute path glob like `/usr/lib/modules/*/initramfs.img` against the sysroot.
+fn resolve_path_glob(
+    sysroot: &Utf8Path,
+    glob: &str,
+) -> Result<BTreeSet<Utf8PathBuf>, anyhow::Error> {
+    if !glob.starts_with('/') {
+        anyhow::bail!("Path glob {} is not absolute", glob);
+    }
+    let regex = glob_to_regex(glob)?;
+    // Only walk the part of the tree the glob can match
+    let base = Utf8Path::new(glob)
+        .components()
+        .take_while(|component| !component.as_str().contains(['*', '?', '[']))
+        .collect::<Utf8PathBuf>();
+    let mut matches = BTreeSet::new();
+    match sysroot
+        .join(base.strip_prefix("/").unwrap())
+        .symlink_metadata()
+    {
+        Ok(metadata) if metadata.is_dir() => walk_matching(sysroot, &base, &regex, &mut matches)?,
+        Ok(_metadata) if regex.is_match(base.as_str()) => {
+            matches.insert(base);
+        }
+        Ok(_metadata) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    if matches.is_empty() {
+        tracing::warn!("Path glob {} did not match any files", glob);
+    }
+    Ok(matches)
+}
+
+/// A virtual package added to the index.
+#[derive(Debug, Deserialize)]
+pub(crate) struct NewPackage {
+    identifier: String,
+    name: String,
+    version: String,
+    source: String,
+    // Size in bytes, calculated from the files in the sysroot if not given
+    size: Option<u64>,
+    // List of files
+    #[serde(default)]
+    files: HashSet<Utf8PathBuf>,
+    // Path globs resolved against the sysroot, e.g. `/usr/lib/modules/*/initramfs.img` or `/usr/share/flatpak/**`
+    #[serde(default)]
+    paths: Vec<String>,
+}
+
+impl NewPackage {
+    fn to_package(&self, sysroot: &Utf8Path) -> Result<Package, anyhow::Error> {
+        let mut files = self.files.clone();
+        for glob in &self.paths {
+            files.extend(resolve_path_glob(sysroot, glob)?);
+        }
+        let size = self
+            .size
+            .unwrap_or_else(|| files.iter().map(|file| file_size(sysroot, file)).sum());
+        Ok(Package {
+            identifier: self.identifier.clone(),
+            name: self.name.clone(),
+            version: self.version.clone(),
+            source: self.source.clone(),
+            size,
+            files,
+            ..Default::default()
+        })
+    }
+}
+
+/// Carve the files matching the given path globs out of `package` into separate sub-packages.
+///
+/// The size of the package is divided according to the sizes of the files in the sysroot, or according to the
+/// number of files if the sysroot does not contain them. Sub-packages share version and source of their parent.
+fn split(
+    mut package: Package,
+    sub_packages: &BTreeMap<String, Vec<String>>,
+    sysroot: &Utf8Path,
+) -> Result<Vec<Package>, anyhow::Error> {
+    let sub_packages = sub_packages
+        .iter()
+        .map(|(name, globs)| {
+            let regexes = globs
+                .iter()
+                .map(|glob| glob_to_regex(glob))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok((name, regexes))
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+
+    // Weigh files by their actual size, if the sysroot contains them
+    let weigh_by_size = package
+        .files
+        .iter()
+        .any(|file| file_size(sysroot, file) > 0);
+    let weight = |file: &Utf8Path| {
+        if weigh_by_size {
+            file_size(sysroot, file)
+        } else {
+            1
+        }
+    };
+    let total_weight: u64 = package.files.iter().map(|file| weight(file)).sum();
+
+    let mut split_files: BTreeMap<&String, HashSet<Utf8PathBuf>> = BTreeMap::new();
+    for file in std::mem::take(&mut package.files) {
+        let mut matching = sub_packages
+            .iter()
+            .filter(|(_name, regexes)| regexes.iter().any(|regex| regex.is_match(file.as_str())));
+        match (matching.next(), matching.next()) {
+            (Some((name, _regexes)), None) => {
+                split_files.entry(*name).or_default().insert(file);
+            }
+            (Some((name, _regexes)), Some((other_name, _other_regexes))) => anyhow::bail!(
+                "File {} of package {} would be split into both {} and {}",
+                file,
+                package.name,
+                name,
+                other_name
+            ),
+            (None, _) => {
+                package.files.insert(file);
+            }
+        }
+    }
+
+    let mut remaining_size = package.size;
+    let mut packages = Vec::new();
+    for (name, files) in split_files {
+        let sub_weight: u64 = files.iter().map(|file| weight(file)).sum();
+        // Safety: The share of the size is never larger than the size itself, so it fits into an u64
+        let size = u64::try_from(
+            u128::from(package.size) * u128::from(sub_weight) / u128::from(total_weight.max(1)),
+        )
+        .unwrap();
+        remaining_size = remaining_size.saturating_sub(size);
+        packages.push(Package {
+            identifier: format!("{} ({})", name, package.identifier),
+            name: name.clone(),
+            version: package.version.clone(),
+            source: package.source.clone(),
+            size,
+            files,
+            split_from: Some(package.name.clone()),
+            ..Default::default()
+        });
+    }
+    package.size = remaining_size;
+    packages.insert(0, package);
+    Ok(packages)
+}
+
+/// Determine which merged package a package ends up in.
+///
+/// Exact names take precedence over globs and regular expressions, so `"python" = ["python"]` wins against
+/// `"python-stack" = ["python*"]`. A package matched by two merge rules at the same level is an error.
+fn merge_target<'a>(
+    merge_packages: &'a BTreeMap<String, Vec<Pattern>>,
+    name: &str,
+) -> Result<Option<&'a String>, anyhow::Error> {
+    for exact in [true, false] {
+        let mut targets = merge_packages.iter().filter(|(_target, patterns)| {
+            patterns
+                .iter()
+                .any(|pattern| pattern.is_exact() == exact && pattern.matches(name))
+        });
+        if let Some((target, _patterns)) = targets.next() {
+            if let Some((other_target, _patterns)) = targets.next() {
+                anyhow::bail!(
+                    "Package {} would be merged into both {} and {}",
+                    name,
+                    target,
+                    other_target
+                );
+            }
+            return Ok(Some(target));
+        }
+    }
+    Ok(None)
+}
+
+/// Merge several packages into a single one with the given name.
+fn merge(name: &str, packages: Vec<Package>) -> Package {
+    let mut merged_package =
+        packages
+            .into_iter()
+            .fold(Package::default(), |mut merged_package, package| {
+                // Merge identifier, source and version fields
+                extend_string_with_separator(
+                    &mut merged_package.identifier,
+                    &package.identifier,
+                    ',',
+                );
+                extend_string_with_separator(&mut merged_package.source, &package.source, ',');
+                extend_string_with_separator(&mut merged_package.version, &package.version, ',');
+                // Sum sizes
+                merged_package.size += package.size;
+                // Take all files from the merged package
+                merged_package.files.extend(package.files);
+                // Keep track of what the merged packages replace and provide
+                merged_package.replaces.extend(package.replaces);
+                merged_package.provides.extend(package.provides);
+                merged_package
+            });
+    // Set the name of the merged package as indicated by the user
+    merged_package.name = name.to_string();
+    merged_package
+}
+
+/// Which packages [`AutoMerge`] groups together.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum AutoMergeMode {
+    /// Packages built from the same source, e.g. the same SRPM
+    Source,
+    /// Packages sharing the part of their name before the prefix separator, e.g. `python-pip` and `python`
+    NamePrefix,
+}
+
+fn default_prefix_separator() -> String {
+    "-".to_string()
+}
+
+/// Rules for merging packages automatically instead of listing them in `merge_packages`.
+#[derive(Debug, Deserialize)]
+pub(crate) struct AutoMerge {
+    by: AutoMergeMode,
+    // Separator ending the name prefix in `name-prefix` mode
+    #[serde(default = "default_prefix_separator")]
+    prefix_separator: String,
+    // Packages larger than this (in bytes) are kept separate, unless they give the merged package its name
+    max_size: Option<u64>,
+    // Packages which are never merged automatically
+    #[serde(default)]
+    exclude: Vec<Pattern>,
+}
+
+impl AutoMerge {
+    /// Key of the group a package belongs to.
+    fn group<'a>(&self, package: &'a Package) -> &'a str {
+        match self.by {
+            AutoMergeMode::Source => package.source.as_str(),
+            AutoMergeMode::NamePrefix => package
+                .name
+                .split_once(self.prefix_separator.as_str())
+                .map_or(package.name.as_str(), |(prefix, _rest)| prefix),
+        }
+    }
+
+    /// Name of the merged package of a group.
+    ///
+    /// In `name-prefix` mode this is the prefix. Sources are usually named like one of their packages, possibly
+    /// followed by version and release (`python3-3.13.1-1.fc42.src.rpm`), so the longest package name the source
+    /// starts with is used, and the source itself if there is none.
+    fn merged_name(&self, group: &str, packages: &[&Package]) -> String {
+        match self.by {
+            AutoMergeMode::Source => packages
+                .iter()
+                .map(|package| package.name.as_str())
+                .filter(|name| {
+                    group == *name
+                        || group
+                            .strip_prefix(name)
+                            .is_some_and(|rest| rest.starts_with('-'))
+                })
+                .max_by_key(|name| name.len())
+                .unwrap_or(group.strip_suffix(".src.rpm").unwrap_or(group))
+                .to_string(),
+            AutoMergeMode::NamePrefix => group.to_string(),
+        }
+    }
+
+    /// Merge the packages of each group, except for `curated` ones created explicitly by other rules.
+    fn apply(
+        &self,
+        index: Vec<Package>,
+        curated: &HashSet<String>,
+    ) -> Result<Vec<Package>, anyhow::Error> {
+        if self.by == AutoMergeMode::NamePrefix && self.prefix_separator.is_empty() {
+            anyhow::bail!("auto_merge: prefix_separator must not be empty");
+        }
+        let eligible = |package: &Package| {
+            !curated.contains(&package.name)
+                && package.split_from.is_none()
+                && !self
+                    .exclude
+                    .iter()
+                    .any(|pattern| pattern.matches(&package.name))
+        };
+        let mut groups: BTreeMap<&str, Vec<&Package>> = BTreeMap::new();
+        for package in index.iter().filter(|package| eligible(package)) {
+            groups.entry(self.group(package)).or_default().push(package);
+        }
+        // Decide on the members of each merged package first, as the groups borrow from the index
+        let mut targets: HashMap<String, String> = HashMap::new();
+        for (group, packages) in &groups {
+            let merged_name = self.merged_name(group, packages);
+            let members = packages
+                .iter()
+                .filter(|package| {
+                    package.name == merged_name
+                        || self
+                            .max_size
+                            .is_none_or(|max_size| package.size <= max_size)
+                })
+                .map(|package| package.name.clone())
+                .collect::<Vec<_>>();
+            if members.len() < 2 {
+                continue;
+            }
+            if !members.contains(&merged_name)
+                && index.iter().any(|package| package.name == merged_name)
+            {
+                tracing::warn!(
+                    "Not merging {} automatically, as {} is already taken by another package",
+                    members.join(", "),
+                    merged_name
+                );
+                continue;
+            }
+            tracing::debug!(
+                "Automatically merging {} into {}",
+                members.join(", "),
+                merged_name
+            );
+            targets.extend(members.into_iter().map(|name| (name, merged_name.clone())));
+        }
+
+        let mut merged: BTreeMap<String, Vec<Package>> = BTreeMap::new();
+        let mut remaining = Vec::with_capacity(index.len());
+        for package in index {
+            match targets.get(&package.name) {
+                Some(target) => merged.entry(target.clone()).or_default().push(package),
+                None => remaining.push(package),
+            }
+        }
+        for (name, packages) in merged {
+            // Keep the common source instead of repeating it for every merged package
+            let source = packages[0].source.clone();
+            let mut package = merge(&name, packages);
+            if self.by == AutoMergeMode::Source {
+                package.source = source;
+            }
+            remaining.push(package);
+        }
+        Ok(remaining)
+    }
+}
+
+/// Metadata forced onto all packages matching one of the patterns.
 #[derive(Debug, Deserialize)]
+pub(crate) struct Override {
+    packages: Vec<Pattern>,
+    change_frequency: Option<ChangeFrequencyClass>,
+    // Offset to the earliest changed package in hours (or builds)
+    change_time_offset: Option<u32>,
+    // Source group, e.g. to group packages with the same update cadence
+    source: Option<String>,
+}
+
+impl Override {
+    fn apply(&self, package: &mut Package) {
+        if !self
+            .packages
+            .iter()
+            .any(|pattern| pattern.matches(&package.name))
+        {
+            return;
+        }
+        if let Some(change_frequency) = self.change_frequency {
+            package.overrides.change_frequency = Some(change_frequency);
+        }
+        if let Some(change_time_offset) = self.change_time_offset {
+            package.overrides.change_time_offset = Some(change_time_offset);
+        }
+        if let Some(source) = &self.source {
+            package.source = source.clone();
+        }
+    }
+}
+
+/// How severe a problem found by [`Postprocessing::validate`] is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Severity {
+    /// The configuration is likely outdated, but can be applied
+    Warning,
+    /// The configuration cannot be applied or would produce a broken index
+    Error,
+}
+
+/// A problem found by [`Postprocessing::validate`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn warning(message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message,
+        }
+    }
+
+    fn error(message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            message,
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning: {}", self.message),
+            Severity::Error => write!(f, "error: {}", self.message),
+        }
+    }
+}
+
+/// Prefix of includes referring to a built-in preset instead of a file
+const PRESET_PREFIX: &str = "preset:";
+
+/// Built-in postprocessing configurations for the supported distributions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub(crate) enum PostprocessingPreset {
+    Archlinux,
+    Fedora,
+}
+
+impl PostprocessingPreset {
+    fn contents(&self) -> &'static str {
+        match self {
+            PostprocessingPreset::Archlinux => include_str!("presets/archlinux.toml"),
+            PostprocessingPreset::Fedora => include_str!("presets/fedora.toml"),
+        }
+    }
+
+    pub(crate) fn get_postprocessing(&self) -> Result<Postprocessing, anyhow::Error> {
+        Postprocessing::load_include(
+            &format!("{}{}", PRESET_PREFIX, self.name()),
+            None,
+            &mut Vec::new(),
+        )
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            PostprocessingPreset::Archlinux => "archlinux",
+            PostprocessingPreset::Fedora => "fedora",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
 pub(crate) struct Postprocessing {
-    new_package: Option<Vec<Package>>,
-    // Merge packages into a new one with name = key and packages to be merged as value
-    merge_packages: Option<HashMap<String, Vec<String>>>,
+    // Configurations this one is layered on top of, either paths (relative to this file) or built-in presets
+    // like `preset:archlinux`. Later includes and this file itself take precedence.
+    include: Option<Vec<String>>,
+    // Packages to drop from the index, e.g. `gpg-pubkey` on RPM based systems
+    exclude_packages: Option<Vec<Pattern>>,
+    // Path globs of files not to attribute to any package, e.g. `/var/**`, which ostree does not ship anyway
+    exclude_paths: Option<Vec<String>>,
+    // Virtual packages, which take over their files from the packages that owned them before
+    new_package: Option<Vec<NewPackage>>,
+    // Split packages into several ones, with the name of the package to split as key. The value maps the names
+    // of the sub-packages to path globs of the files they take over from the package.
+    split_packages: Option<HashMap<String, BTreeMap<String, Vec<String>>>>,
+    // Merge packages into a new one with name = key and packages to be merged as value.
+    // Packages may be given by name, glob (e.g. `python-*`) or regular expression (e.g. `re:python(-.*)?`).
+    merge_packages: Option<BTreeMap<String, Vec<Pattern>>>,
+    // Merge packages sharing their source or name prefix, after the explicit merges
+    auto_merge: Option<AutoMerge>,
+    // Explicit renames with the previous package name as key and the current name as value.
+    // The history of the previous package is transferred to the renamed one.
+    rename_packages: Option<HashMap<String, String>>,
+    // Metadata overrides, applied to the final packages. Later entries take precedence.
+    overrides: Option<Vec<Override>>,
 }
 
 impl Postprocessing {
     pub(crate) fn new_from_toml<P: AsRef<Path>>(path: P) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
         let mut file = File::open(path)?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
-        Ok(toml::from_str(&contents)?)
+        Self::new_from_str(
+            &contents,
+            path.parent(),
+            &mut vec![path.canonicalize()?.display().to_string()],
+        )
+    }
+
+    /// Load the postprocessing configuration from a preset, a file, or a file layered on top of a preset.
+    pub(crate) fn load(
+        preset: Option<PostprocessingPreset>,
+        path: Option<&Utf8Path>,
+    ) -> Result<Option<Self>, anyhow::Error> {
+        let mut postprocessing = match preset {
+            Some(preset) => Some(preset.get_postprocessing()?),
+            None => None,
+        };
+        if let Some(path) = path {
+            let overlay = Self::new_from_toml(path)?;
+            match &mut postprocessing {
+                Some(postprocessing) => postprocessing.extend(overlay),
+                None => postprocessing = Some(overlay),
+            }
+        }
+        Ok(postprocessing)
+    }
+
+    /// Parse a configuration and resolve its includes. `stack` holds the includes currently being loaded.
+    fn new_from_str(
+        contents: &str,
+        base_dir: Option<&Path>,
+        stack: &mut Vec<String>,
+    ) -> Result<Self, anyhow::Error> {
+        let mut postprocessing: Postprocessing = toml::from_str(contents)?;
+        let mut combined = Postprocessing::default();
+        for include in postprocessing.include.take().unwrap_or_default() {
+            combined.extend(Self::load_include(&include, base_dir, stack)?);
+        }
+        combined.extend(postprocessing);
+        Ok(combined)
     }
 
-    pub(crate) fn apply(self, mut index: Vec<Package>) -> Result<Vec<Package>, anyhow::Error> {
-        if let Some(new_packages) = self.new_package {
+    fn load_include(
+        include: &str,
+        base_dir: Option<&Path>,
+        stack: &mut Vec<String>,
+    ) -> Result<Self, anyhow::Error> {
+        let (key, contents, base_dir) = match include.strip_prefix(PRESET_PREFIX) {
+            Some(name) => {
+                let preset = PostprocessingPreset::from_str(name, true)
+                    .map_err(|_e| anyhow::anyhow!("Unknown postprocessing preset {}", name))?;
+                (include.to_string(), preset.contents().to_string(), None)
+            }
+            None => {
+                let path = base_dir.unwrap_or(Path::new("")).join(include);
+                let contents = std::fs::read_to_string(&path).map_err(|e| {
+                    anyhow::anyhow!(
+                        "Failed to read included postprocessing file {}: {}",
+                        path.display(),
+                        e
+                    )
+                })?;
+                let base_dir = path.parent().map(Path::to_path_buf);
+                (
+                    path.canonicalize()?.display().to_string(),
+                    contents,
+                    base_dir,
+                )
+            }
+        };
+        if stack.contains(&key) {
+            anyhow::bail!(
+                "Postprocessing include cycle: {} -> {}",
+                stack.join(" -> "),
+                key
+            );
+        }
+        tracing::debug!("Including postprocessing configuration {}", key);
+        stack.push(key);
+        let postprocessing = Self::new_from_str(&contents, base_dir.as_deref(), stack)?;
+        stack.pop();
+        Ok(postprocessing)
+    }
+
+    /// Layer `overlay` on top of this configuration.
+    ///
+    /// Lists are appended, while entries with the same name (virtual packages, split and merge rules, renames) are
+    /// replaced by the overlay. Empty split and merge rules remove the rule altogether.
+    fn extend(&mut self, overlay: Postprocessing) {
+        fn append<T>(base: &mut Option<Vec<T>>, overlay: Option<Vec<T>>) {
+            if let Some(overlay) = overlay {
+                base.get_or_insert_default().extend(overlay);
+            }
+        }
+
+        append(&mut self.exclude_packages, overlay.exclude_packages);
+        append(&mut self.exclude_paths, overlay.exclude_paths);
+        if let Some(new_packages) = overlay.new_package {
+            let base = self.new_package.get_or_insert_default();
+            base.retain(|package| !new_packages.iter().any(|new| new.name == package.name));
+            base.extend(new_packages);
+        }
+        if let Some(split_packages) = overlay.split_packages {
+            let base = self.split_packages.get_or_insert_default();
+            base.extend(split_packages);
+            base.retain(|_parent, sub_packages| !sub_packages.is_empty());
+        }
+        if let Some(merge_packages) = overlay.merge_packages {
+            let base = self.merge_packages.get_or_insert_default();
+            base.extend(merge_packages);
+            base.retain(|_target, patterns| !patterns.is_empty());
+        }
+        if overlay.auto_merge.is_some() {
+            self.auto_merge = overlay.auto_merge;
+        }
+        if let Some(rename_packages) = overlay.rename_packages {
+            self.rename_packages
+                .get_or_insert_default()
+                .extend(rename_packages);
+        }
+        append(&mut self.overrides, overlay.overrides);
+    }
+
+    pub(crate) fn apply(
+        &self,
+        mut index: Vec<Package>,
+        sysroot: &Utf8Path,
+    ) -> Result<Vec<Package>, anyhow::Error> {
+        if let Some(exclude_packages) = &self.exclude_packages {
+            let count = index.len();
+            index.retain(|package| {
+                !exclude_packages
+                    .iter()
+                    .any(|pattern| pattern.matches(&package.name))
+            });
+            tracing::debug!("Excluded {} packages from the index", count - index.len());
+        }
+        if let Some(new_packages) = &self.new_package {
+            let new_packages = new_packages
+                .iter()
+                .map(|package| package.to_package(sysroot))
+                .collect::<Result<Vec<_>, _>>()?;
+            // Files of virtual packages are removed from their original owners
+            let claimed = new_packages
+                .iter()
+                .flat_map(|package| package.files.iter())
+                .collect::<HashSet<_>>();
+            for package in index.iter_mut() {
+                remove_files(package, sysroot, |file| claimed.contains(file));
+            }
             index.extend(new_packages);
         }
-        if let Some(merge_packages) = self.merge_packages {
-            for (new_name, packages_to_merge) in merge_packages {
-                // Find the affected packages and remember their indices.
-                // Create a merged package inside the `fold` part.
-                let (indices, mut merged_package) = index
-                    .iter_mut()
-                    .enumerate()
-                    .filter(|(_i, pkg)| packages_to_merge.contains(&pkg.name))
-                    .fold(
-                        (Vec::new(), Package::default()),
-                        |(mut indices, mut merged_package), (i, package)| {
-                            // Remember index to remove it later
-                            indices.push(i);
-                            // Merge identifier, source and version fields
-                            extend_string_with_separator(
-                                &mut merged_package.identifier,
-                                &package.identifier,
-                                ',',
-                            );
-                            extend_string_with_separator(
-                                &mut merged_package.source,
-                                &package.source,
-                                ',',
-                            );
-                            extend_string_with_separator(
-                                &mut merged_package.version,
-                                &package.version,
-                                ',',
-                            );
-                            // Sum sizes
-                            merged_package.size += package.size;
-                            // Take all files from the merged package.
-                            // This will empty the files list, which is fine, because we will remove it later, anyway
-                            merged_package.files = merged_package
-                                .files
-                                .union(&package.files)
-                                .cloned()
-                                .collect();
-                            (indices, merged_package)
-                        },
-                    );
-                // Set the name of the merged package as indicated by the user
-                merged_package.name = new_name;
-                // Delete the packages that were just merged from the index
-                for i in 0..indices.len() {
-                    let _ = index.remove(indices[i] - i);
+        if let Some(exclude_paths) = &self.exclude_paths {
+            let regexes = exclude_paths
+                .iter()
+                .map(|glob| glob_to_regex(glob))
+                .collect::<Result<Vec<_>, _>>()?;
+            let removed: usize = index
+                .iter_mut()
+                .map(|package| {
+                    remove_files(package, sysroot, |file| {
+                        regexes.iter().any(|regex| regex.is_match(file.as_str()))
+                    })
+                })
+                .sum();
+            tracing::debug!("Excluded {} files from the index", removed);
+        }
+        if let Some(split_packages) = &self.split_packages {
+            index = index
+                .into_iter()
+                .map(|package| match split_packages.get(&package.name) {
+                    Some(sub_packages) => split(package, sub_packages, sysroot),
+                    None => Ok(vec![package]),
+                })
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .flatten()
+                .collect();
+        }
+        if let Some(merge_packages) = &self.merge_packages {
+            let targets = index
+                .iter()
+                .map(|package| merge_target(merge_packages, &package.name))
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut groups: BTreeMap<&String, Vec<Package>> = BTreeMap::new();
+            let mut remaining = Vec::with_capacity(index.len());
+            for (package, target) in index.into_iter().zip(targets) {
+                match target {
+                    Some(target) => groups.entry(target).or_default().push(package),
+                    None => remaining.push(package),
+                }
+            }
+            index = remaining;
+            for new_name in merge_packages.keys() {
+                match groups.remove(new_name) {
+                    Some(packages) => index.push(merge(new_name, packages)),
+                    None => tracing::warn!("No packages to merge into {}", new_name),
+                }
+            }
+        }
+        if let Some(auto_merge) = &self.auto_merge {
+            // Leave packages alone which were already shaped by hand
+            let curated = self
+                .new_package
+                .iter()
+                .flatten()
+                .map(|package| package.name.clone())
+                .chain(
+                    self.merge_packages
+                        .iter()
+                        .flat_map(|merge| merge.keys().cloned()),
+                )
+                .collect::<HashSet<_>>();
+            let count = index.len();
+            index = auto_merge.apply(index, &curated)?;
+            tracing::debug!(
+                "Automatic merging reduced the index by {} packages",
+                count - index.len()
+            );
+        }
+        if let Some(rename_packages) = &self.rename_packages {
+            for (previous_name, current_name) in rename_packages {
+                if let Some(package) = index.iter_mut().find(|pkg| &pkg.name == current_name) {
+                    package.replaces.insert(previous_name.clone());
+                }
+            }
+        }
+        if let Some(overrides) = &self.overrides {
+            for package in index.iter_mut() {
+                for r#override in overrides {
+                    r#override.apply(package);
                 }
-                index.push(merged_package);
             }
         }
         Ok(index)
     }
+
+    /// Apply the configuration like [`Postprocessing::apply`], checking the rules and the resulting index.
+    ///
+    /// Rules referring to packages or files that do not exist are reported as warnings, as they are usually
+    /// outdated and `apply` skips them. Rules that cannot be applied unambiguously, or would produce a broken index,
+    /// are errors.
+    pub(crate) fn apply_and_validate(
+        &self,
+        index: Vec<Package>,
+        sysroot: &Utf8Path,
+    ) -> (Vec<Diagnostic>, Result<Vec<Package>, anyhow::Error>) {
+        let mut diagnostics = self.validate(&index, sysroot);
+        let result = self.apply(index, sysroot);
+        match &result {
+            Ok(result) => diagnostics.extend(self.validate_result(result)),
+            Err(e) => diagnostics.push(Diagnostic::error(format!(
+                "Failed to apply postprocessing: {}",
+                e
+            ))),
+        }
+        (diagnostics, result)
+    }
+
+    /// Check the rules against the packages they are applied to.
+    fn validate(&self, index: &[Package], sysroot: &Utf8Path) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        let unmatched = |patterns: &[Pattern], names: &BTreeSet<&str>| {
+            patterns
+                .iter()
+                .filter(|pattern| !names.iter().any(|name| pattern.matches(name)))
+                .map(|pattern| pattern.as_str().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let mut names = index
+            .iter()
+            .map(|package| package.name.as_str())
+            .collect::<BTreeSet<_>>();
+        for pattern in unmatched(self.exclude_packages.as_deref().unwrap_or_default(), &names) {
+            diagnostics.push(Diagnostic::warning(format!(
+                "exclude_packages: {} does not match any package",
+                pattern
+            )));
+        }
+        for new_package in self.new_package.iter().flatten() {
+            for file in &new_package.files {
+                if sysroot
+                    .join(file.strip_prefix("/").unwrap_or(file))
+                    .symlink_metadata()
+                    .is_err()
+                {
+                    diagnostics.push(Diagnostic::warning(format!(
+                        "new_package {}: {} does not exist in the sysroot",
+                        new_package.name, file
+                    )));
+                }
+            }
+            for glob in &new_package.paths {
+                match resolve_path_glob(sysroot, glob) {
+                    Ok(files) if files.is_empty() => {
+                        diagnostics.push(Diagnostic::warning(format!(
+                            "new_package {}: {} does not match any file",
+                            new_package.name, glob
+                        )))
+                    }
+                    Ok(_files) => {}
+                    Err(e) => diagnostics.push(Diagnostic::error(format!(
+                        "new_package {}: {}",
+                        new_package.name, e
+                    ))),
+                }
+            }
+            if names.contains(new_package.name.as_str()) {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "new_package {}: there already is a package with that name",
+                    new_package.name
+                )));
+            }
+            names.insert(&new_package.name);
+        }
+        for (parent, sub_packages) in self.split_packages.iter().flatten() {
+            match index.iter().find(|package| &package.name == parent) {
+                Some(package) => {
+                    for (name, globs) in sub_packages {
+                        for glob in globs {
+                            match glob_to_regex(glob) {
+                                Ok(regex)
+                                    if !package
+                                        .files
+                                        .iter()
+                                        .any(|file| regex.is_match(file.as_str())) =>
+                                {
+                                    diagnostics.push(Diagnostic::warning(format!(
+                                        "split_packages {}: {} of {} does not match any file",
+                                        parent, glob, name
+                                    )))
+                                }
+                                Ok(_regex) => {}
+                                Err(e) => diagnostics.push(Diagnostic::error(format!(
+                                    "split_packages {}: {}",
+                                    parent, e
+                                ))),
+                            }
+                        }
+                    }
+                }
+                None => diagnostics.push(Diagnostic::warning(format!(
+                    "split_packages: {} does not match any package",
+                    parent
+                ))),
+            }
+            names.extend(sub_packages.keys().map(String::as_str));
+        }
+        for (target, patterns) in self.merge_packages.iter().flatten() {
+            let unmatched_patterns = unmatched(patterns.as_slice(), &names);
+            if unmatched_patterns.len() == patterns.len() {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "merge_packages {}: none of the packages exist, no package will be created",
+                    target
+                )));
+            } else {
+                for pattern in unmatched_patterns {
+                    diagnostics.push(Diagnostic::warning(format!(
+                        "merge_packages {}: {} does not match any package",
+                        target, pattern
+                    )));
+                }
+            }
+        }
+        if let Some(merge_packages) = &self.merge_packages {
+            for name in &names {
+                if let Err(e) = merge_target(merge_packages, name) {
+                    diagnostics.push(Diagnostic::error(format!("merge_packages: {}", e)));
+                }
+            }
+        }
+        diagnostics
+    }
+
+    /// Check the outcome of the whole postprocessing.
+    fn validate_result(&self, result: &[Package]) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        let mut identifiers = HashSet::new();
+        for package in result {
+            if !identifiers.insert(package.identifier.as_str()) {
+                diagnostics.push(Diagnostic::error(format!(
+                    "Duplicate package identifier {} (package {})",
+                    package.identifier, package.name
+                )));
+            }
+        }
+        let names = result
+            .iter()
+            .map(|package| package.name.as_str())
+            .collect::<BTreeSet<_>>();
+        for (previous_name, current_name) in self.rename_packages.iter().flatten() {
+            if !names.contains(current_name.as_str()) {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "rename_packages {}: {} does not match any package",
+                    previous_name, current_name
+                )));
+            }
+        }
+        for r#override in self.overrides.iter().flatten() {
+            for pattern in r#override
+                .packages
+                .iter()
+                .filter(|pattern| !names.iter().any(|name| pattern.matches(name)))
+            {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "overrides: {} does not match any package",
+                    pattern.as_str()
+                )));
+            }
+        }
+        diagnostics
+    }
+}
+
+/// Report diagnostics and fail on errors (or on warnings in strict mode).
+pub(crate) fn check_diagnostics(
+    diagnostics: &[Diagnostic],
+    strict: bool,
+) -> Result<(), anyhow::Error> {
+    for diagnostic in diagnostics {
+        match diagnostic.severity {
+            Severity::Warning => tracing::warn!("{}", diagnostic.message),
+            Severity::Error => tracing::error!("{}", diagnostic.message),
+        }
+    }
+    let threshold = if strict {
+        Severity::Warning
+    } else {
+        Severity::Error
+    };
+    let failed = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity >= threshold)
+        .count();
+    if failed > 0 {
+        anyhow::bail!("Postprocessing configuration has {} problem(s)", failed);
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -114,6 +1029,9 @@ files = [
 "basepkg" = ["base", "filesystem"]
 "certificates" = ["ca-certificates", "ca-certificates-mozilla", "ca-certificates-utils"]
 "dbuspkg" = ["dbus", "dbus-broker", "dbus-units"]
+
+[rename_packages]
+"pipewire-media-session" = "wireplumber"
 "#;
 
         let post: Postprocessing = toml::from_str(toml_str).expect("Failed to deserialize TOML");
@@ -128,7 +1046,7 @@ files = [
         assert_eq!(pkgs[0].name, "initramfs");
         assert_eq!(pkgs[0].version, "1");
         assert_eq!(pkgs[0].source, "initramfs");
-        assert_eq!(pkgs[0].size, 209715200);
+        assert_eq!(pkgs[0].size, Some(209715200));
         assert_eq!(pkgs[0].files.len(), 1);
         assert!(pkgs[0].files.contains(
             &Utf8PathBuf::from_str("/usr/lib/modules/6.15.9-arch1-1/initramfs.img").unwrap()
@@ -140,17 +1058,434 @@ files = [
             .expect("Expected merge_packages to be present");
         assert_eq!(merges.len(), 3);
 
+        let patterns = |name: &str| {
+            merges
+                .get(name)
+                .unwrap()
+                .iter()
+                .map(Pattern::as_str)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(patterns("basepkg"), ["base", "filesystem"]);
+        assert_eq!(
+            patterns("certificates"),
+            [
+                "ca-certificates",
+                "ca-certificates-mozilla",
+                "ca-certificates-utils"
+            ]
+        );
+
+        // Validate rename_packages
+        let renames = post
+            .rename_packages
+            .expect("Expected rename_packages to be present");
+        assert_eq!(
+            renames.get("pipewire-media-session").unwrap(),
+            "wireplumber"
+        );
+    }
+
+    #[test]
+    fn test_presets() {
+        for preset in PostprocessingPreset::value_variants() {
+            let post = preset.get_postprocessing().unwrap();
+            assert!(post.merge_packages.unwrap().contains_key("basepkg"));
+        }
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("common.toml"),
+            r#"
+include = ["preset:archlinux"]
+exclude_packages = ["gpg-pubkey"]
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("image.toml"),
+            r#"
+include = ["common.toml"]
+exclude_packages = ["linux-api-headers"]
+
+[merge_packages]
+"dbuspkg" = ["dbus", "dbus-broker"]
+"firmware" = []
+"#,
+        )
+        .unwrap();
+        let post = Postprocessing::new_from_toml(dir.path().join("image.toml")).unwrap();
+
+        let exclude_packages = post
+            .exclude_packages
+            .iter()
+            .flatten()
+            .map(Pattern::as_str)
+            .collect::<Vec<_>>();
+        assert_eq!(exclude_packages, ["gpg-pubkey", "linux-api-headers"]);
+        assert!(post.include.is_none());
+        assert_eq!(post.new_package.unwrap()[0].name, "initramfs");
+        let merges = post.merge_packages.unwrap();
+        assert_eq!(
+            merges.keys().collect::<Vec<_>>(),
+            ["basepkg", "certificates", "dbuspkg"]
+        );
+        assert_eq!(merges["dbuspkg"].len(), 2);
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.toml"), r#"include = ["b.toml"]"#).unwrap();
+        std::fs::write(dir.path().join("b.toml"), r#"include = ["a.toml"]"#).unwrap();
+        assert!(Postprocessing::new_from_toml(dir.path().join("a.toml")).is_err());
+    }
+
+    #[test]
+    fn test_apply_rename_packages() {
+        let post: Postprocessing = toml::from_str(
+            r#"
+[rename_packages]
+"pipewire-media-session" = "wireplumber"
+"#,
+        )
+        .unwrap();
+        let index = vec![Package {
+            identifier: "wireplumber-0.5".to_string(),
+            name: "wireplumber".to_string(),
+            ..Default::default()
+        }];
+        let index = post.apply(index, Utf8Path::new("/")).unwrap();
+        assert!(index[0].replaces.contains("pipewire-media-session"));
+    }
+
+    fn package(name: &str) -> Package {
+        Package {
+            identifier: format!("{}-1", name),
+            name: name.to_string(),
+            version: "1".to_string(),
+            source: name.to_string(),
+            size: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_apply_merge_patterns() {
+        let post: Postprocessing = toml::from_str(
+            r#"
+[merge_packages]
+"python-stack" = ["python", "python-*"]
+"pip" = ["python-pip"]
+"#,
+        )
+        .unwrap();
+        let index = vec![
+            package("python"),
+            package("python-setuptools"),
+            package("python-pip"),
+            package("bash"),
+        ];
+        let index = post.apply(index, Utf8Path::new("/")).unwrap();
+        let names = index
+            .iter()
+            .map(|pkg| pkg.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["bash", "pip", "python-stack"]);
+        assert_eq!(index[2].identifier, "python-1,python-setuptools-1");
+        assert_eq!(index[2].size, 2);
+    }
+
+    #[test]
+    fn test_apply_merge_conflict() {
+        let post: Postprocessing = toml::from_str(
+            r#"
+[merge_packages]
+"python-stack" = ["python-*"]
+"pip" = ["re:python-pip.*"]
+"#,
+        )
+        .unwrap();
+        assert!(
+            post.apply(vec![package("python-pip")], Utf8Path::new("/"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_apply_new_package_paths() {
+        let sysroot = tempfile::tempdir().unwrap();
+        let sysroot = Utf8Path::from_path(sysroot.path()).unwrap();
+        std::fs::create_dir_all(sysroot.join("usr/lib/modules/6.15.9-arch1-1")).unwrap();
+        std::fs::write(
+            sysroot.join("usr/lib/modules/6.15.9-arch1-1/initramfs.img"),
+            [0u8; 10],
+        )
+        .unwrap();
+        std::fs::write(
+            sysroot.join("usr/lib/modules/6.15.9-arch1-1/vmlinuz"),
+            [0u8; 5],
+        )
+        .unwrap();
+
+        let post: Postprocessing = toml::from_str(
+            r#"
+[[new_package]]
+identifier = "initramfs"
+name = "initramfs"
+version = "1"
+source = "initramfs"
+paths = ["/usr/lib/modules/*/initramfs.img"]
+"#,
+        )
+        .unwrap();
+        let mut linux = package("linux");
+        linux.size = 100;
+        linux.files = HashSet::from([
+            Utf8PathBuf::from("/usr/lib/modules/6.15.9-arch1-1/initramfs.img"),
+            Utf8PathBuf::from("/usr/lib/modules/6.15.9-arch1-1/vmlinuz"),
+        ]);
+        let index = post.apply(vec![linux], sysroot).unwrap();
+
+        assert_eq!(index[0].size, 90);
+        assert_eq!(index[0].files.len(), 1);
+        assert_eq!(index[1].name, "initramfs");
+        assert_eq!(index[1].size, 10);
+        assert!(index[1].files.contains(Utf8Path::new(
+            "/usr/lib/modules/6.15.9-arch1-1/initramfs.img"
+        )));
+    }
+
+    #[test]
+    fn test_apply_split_packages() {
+        let post: Postprocessing = toml::from_str(
+            r#"
+[split_packages.linux]
+"linux-modules" = ["/usr/lib/modules/*/kernel/**"]
+"#,
+        )
+        .unwrap();
+        let mut linux = package("linux");
+        linux.size = 300;
+        linux.files = HashSet::from([
+            Utf8PathBuf::from("/usr/lib/modules/6.15.9-arch1-1/vmlinuz"),
+            Utf8PathBuf::from("/usr/lib/modules/6.15.9-arch1-1/kernel/fs/btrfs.ko"),
+            Utf8PathBuf::from("/usr/lib/modules/6.15.9-arch1-1/kernel/fs/ext4.ko"),
+        ]);
+        // The sysroot does not contain the files, so the size is divided by the number of files
+        let index = post
+            .apply(vec![linux], Utf8Path::new("/nonexistent"))
+            .unwrap();
+
+        assert_eq!(index.len(), 2);
+        assert_eq!(index[0].name, "linux");
+        assert_eq!(index[0].size, 100);
+        assert_eq!(index[0].files.len(), 1);
+        assert_eq!(index[1].name, "linux-modules");
+        assert_eq!(index[1].identifier, "linux-modules (linux-1)");
+        assert_eq!(index[1].size, 200);
+        assert_eq!(index[1].files.len(), 2);
+        assert_eq!(index[1].split_from.as_deref(), Some("linux"));
+    }
+
+    #[test]
+    fn test_apply_excludes() {
+        let post: Postprocessing = toml::from_str(
+            r#"
+exclude_packages = ["gpg-pubkey"]
+exclude_paths = ["/var/**"]
+"#,
+        )
+        .unwrap();
+        let mut bash = package("bash");
+        bash.files = HashSet::from([
+            Utf8PathBuf::from("/usr/bin/bash"),
+            Utf8PathBuf::from("/var/lib/bash/state"),
+        ]);
+        let index = post
+            .apply(
+                vec![package("gpg-pubkey"), bash],
+                Utf8Path::new("/nonexistent"),
+            )
+            .unwrap();
+
+        assert_eq!(index.len(), 1);
+        assert_eq!(index[0].name, "bash");
         assert_eq!(
-            merges.get("basepkg").unwrap(),
-            &vec!["base".to_string(), "filesystem".to_string()]
+            index[0].files,
+            HashSet::from([Utf8PathBuf::from("/usr/bin/bash")])
         );
+    }
+
+    #[test]
+    fn test_auto_merge_by_source() {
+        let post: Postprocessing = toml::from_str(
+            r#"
+[auto_merge]
+by = "source"
+max_size = 10
+"#,
+        )
+        .unwrap();
+        let with_source = |name: &str, source: &str, size: u64| Package {
+            source: source.to_string(),
+            size,
+            ..package(name)
+        };
+        let index = vec![
+            with_source("python3", "python3-3.13.1-1.fc42.src.rpm", 100),
+            with_source("python3-libs", "python3-3.13.1-1.fc42.src.rpm", 50),
+            with_source("python3-devel", "python3-3.13.1-1.fc42.src.rpm", 5),
+            with_source("python3-tkinter", "python3-3.13.1-1.fc42.src.rpm", 1),
+            with_source("bash", "bash-5.2.37-1.fc42.src.rpm", 1),
+        ];
+        let index = post.apply(index, Utf8Path::new("/")).unwrap();
+        let names = index
+            .iter()
+            .map(|pkg| pkg.name.as_str())
+            .collect::<Vec<_>>();
+        // The large python3-libs stays separate, the tiny packages join the main package
+        assert_eq!(names, ["python3-libs", "bash", "python3"]);
         assert_eq!(
-            merges.get("certificates").unwrap(),
-            &vec![
-                "ca-certificates".to_string(),
-                "ca-certificates-mozilla".to_string(),
-                "ca-certificates-utils".to_string()
+            index[2].identifier,
+            "python3-1,python3-devel-1,python3-tkinter-1"
+        );
+        assert_eq!(index[2].source, "python3-3.13.1-1.fc42.src.rpm");
+        assert_eq!(index[2].size, 106);
+    }
+
+    #[test]
+    fn test_auto_merge_by_name_prefix() {
+        let post: Postprocessing = toml::from_str(
+            r#"
+[merge_packages]
+"certificates" = ["ca-certificates", "ca-certificates-*"]
+
+[auto_merge]
+by = "name-prefix"
+exclude = ["lib32-*"]
+"#,
+        )
+        .unwrap();
+        let index = vec![
+            package("xorg-server"),
+            package("xorg-xauth"),
+            package("lib32-glibc"),
+            package("lib32-gcc-libs"),
+            package("ca-certificates-utils"),
+            package("ca-certificates-mozilla"),
+            package("ca"),
+        ];
+        let index = post.apply(index, Utf8Path::new("/")).unwrap();
+        let names = index
+            .iter()
+            .map(|pkg| pkg.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "lib32-glibc",
+                "lib32-gcc-libs",
+                "ca",
+                "certificates",
+                "xorg"
             ]
         );
+        assert_eq!(index[4].identifier, "xorg-server-1,xorg-xauth-1");
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        let post: Postprocessing = toml::from_str(
+            r#"
+[[overrides]]
+packages = ["linux", "*-git"]
+change_frequency = "always"
+
+[[overrides]]
+packages = ["tzdata"]
+change_frequency = "never"
+change_time_offset = 0
+source = "tzdata-group"
+"#,
+        )
+        .unwrap();
+        let index = post
+            .apply(
+                vec![package("linux"), package("mesa-git"), package("tzdata")],
+                Utf8Path::new("/"),
+            )
+            .unwrap();
+
+        assert_eq!(
+            index[0].overrides.change_frequency,
+            Some(ChangeFrequencyClass::Always)
+        );
+        assert_eq!(
+            index[1].overrides.change_frequency,
+            Some(ChangeFrequencyClass::Always)
+        );
+        assert_eq!(
+            index[2].overrides.change_frequency,
+            Some(ChangeFrequencyClass::Never)
+        );
+        assert_eq!(index[2].overrides.change_time_offset, Some(0));
+        assert_eq!(index[2].source, "tzdata-group");
+    }
+
+    #[test]
+    fn test_validate() {
+        let post: Postprocessing = toml::from_str(
+            r#"
+[[new_package]]
+identifier = "bash-1"
+name = "initramfs"
+version = "1"
+source = "initramfs"
+files = ["/usr/lib/modules/6.15.9-arch1-1/initramfs.img"]
+
+[merge_packages]
+"basepkg" = ["base", "filesystem"]
+"shells" = ["bash", "zsh"]
+"#,
+        )
+        .unwrap();
+        let (diagnostics, result) = post.apply_and_validate(
+            vec![package("bash"), package("base")],
+            Utf8Path::new("/nonexistent"),
+        );
+        assert!(result.is_ok());
+        let messages = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                "warning: new_package initramfs: /usr/lib/modules/6.15.9-arch1-1/initramfs.img does not exist in the sysroot",
+                "warning: merge_packages basepkg: filesystem does not match any package",
+                "warning: merge_packages shells: zsh does not match any package",
+                "error: Duplicate package identifier bash-1 (package shells)",
+            ]
+        );
+        assert!(check_diagnostics(&diagnostics, false).is_err());
+    }
+
+    #[test]
+    fn test_apply_skips_empty_merge() {
+        let post: Postprocessing = toml::from_str(
+            r#"
+[merge_packages]
+"dbuspkg" = ["dbus", "dbus-broker"]
+"#,
+        )
+        .unwrap();
+        let index = post
+            .apply(vec![package("bash")], Utf8Path::new("/"))
+            .unwrap();
+        assert_eq!(index.len(), 1);
     }
 }
@@ -1,4 +1,5 @@
 use std::{
+    collections::BTreeSet,
     io::{BufRead, BufReader},
     path::{Path, PathBuf},
     process::{Command, Stdio},
@@ -9,27 +10,46 @@ use camino::{Utf8Path, Utf8PathBuf};
 
 use crate::pkgdb::{Package, PackageDatabase, PackageDatabaseWithDefaultPath};
 
-const QUERY_FORMAT: &str = "%{nevra},%{name},%{version},%{sourcerpm},%{size}\\n";
+// Dependency names can't contain whitespace, so the arrays are space separated and split from each other by a tab
+const QUERY_FORMAT: &str =
+    "%{nevra},%{name},%{version},%{sourcerpm},%{size},[%{PROVIDENAME} ]\\t[%{OBSOLETENAME} ]\\n";
+
+/// Collects the package names from a space separated list of dependencies.
+///
+/// File and library dependencies like `libfoo.so.1()(64bit)`, which do not name a package, are left out.
+fn dependency_names(dependencies: &str) -> BTreeSet<String> {
+    dependencies
+        .split_whitespace()
+        .filter(|name| !name.contains('(') && !name.starts_with('/'))
+        .map(str::to_string)
+        .collect()
+}
 
 /// Parses RPM query output into a `PackageRpmQa` struct.
 ///
-/// Expects an iterator of strings that represent lines from `rpm -qa` output
-/// in the format: `nevra,name,version,sourcerpm,size`
-fn get_components<I: Iterator<Item = String>>(mut it: I) -> Result<PackageRpmQa, anyhow::Error> {
+/// Expects a line of `rpm -qa` output in the format: `nevra,name,version,sourcerpm,size,provides\tobsoletes`
+fn get_components(line: &str) -> Result<PackageRpmQa, anyhow::Error> {
     let unexpected_rpm_output = || anyhow::Error::msg("unexpected rpm output");
-    let (identifier, name, version, source, size) = (
+    let mut it = line.splitn(6, ',');
+    let (identifier, name, version, source, size, dependencies) = (
+        it.next().ok_or_else(unexpected_rpm_output)?,
         it.next().ok_or_else(unexpected_rpm_output)?,
         it.next().ok_or_else(unexpected_rpm_output)?,
         it.next().ok_or_else(unexpected_rpm_output)?,
         it.next().ok_or_else(unexpected_rpm_output)?,
         it.next().ok_or_else(unexpected_rpm_output)?,
     );
+    let (provides, obsoletes) = dependencies
+        .split_once('\t')
+        .ok_or_else(unexpected_rpm_output)?;
     Ok(PackageRpmQa {
-        identifier,
-        name,
-        version,
-        source,
+        identifier: identifier.to_string(),
+        name: name.to_string(),
+        version: version.to_string(),
+        source: source.to_string(),
         size: size.parse()?,
+        replaces: dependency_names(obsoletes),
+        provides: dependency_names(provides),
     })
 }
 
@@ -55,7 +75,7 @@ impl RpmDb {
     /// returns a vector of `PackageRpmQa` structs containing all the
     /// needed information with the exception of the file list.
     fn query_metadata(&self) -> Result<Vec<PackageRpmQa>, anyhow::Error> {
-        let child = Command::new("/usr/bin/rpm")
+        let mut child = Command::new("/usr/bin/rpm")
             .arg("--dbpath")
             .arg(self.database.clone())
             .arg("-q")
@@ -67,11 +87,16 @@ impl RpmDb {
         let packages = BufReader::new(
             child
                 .stdout
+                .take()
                 .ok_or(anyhow::Error::msg("rpm command had no stdout"))?,
         )
         .lines()
-        .map(|l| get_components(l?.split(",").map(|s| s.to_string())))
+        .map(|l| get_components(&l?))
         .collect::<Result<Vec<PackageRpmQa>, anyhow::Error>>()?;
+        let status = child.wait()?;
+        if !status.success() {
+            anyhow::bail!("rpm -qa failed: {}", status);
+        }
 
         Ok(packages)
     }
@@ -113,6 +138,12 @@ struct PackageRpmQa {
 
     // Size in bytes
     size: u64,
+
+    // Names of obsoleted packages
+    replaces: BTreeSet<String>,
+
+    // Names of provided (virtual) packages
+    provides: BTreeSet<String>,
 }
 
 impl PackageRpmQa {
@@ -127,6 +158,9 @@ impl PackageRpmQa {
                 .into_iter()
                 .map(|p| p.as_ref().to_path_buf())
                 .collect(),
+            replaces: self.replaces,
+            provides: self.provides,
+            ..Default::default()
         }
     }
 }
@@ -155,7 +189,24 @@ impl PackageDatabaseWithDefaultPath for RpmDb {
 mod test {
     use std::fs::File;
 
-    use crate::pkgdb::{PackageDatabase, rpm::RpmDb};
+    use crate::pkgdb::{
+        PackageDatabase,
+        rpm::{RpmDb, get_components},
+    };
+
+    #[test]
+    fn test_get_components() {
+        let line = "foo-1.0-1.x86_64,foo,1.0,foo-1.0-1.src.rpm,42,foo foo(x86-64) libfoo.so.1()(64bit) /usr/bin/foo \tfoo-old bar ";
+        let package = get_components(line).unwrap();
+        assert_eq!(package.identifier, "foo-1.0-1.x86_64");
+        assert_eq!(package.size, 42);
+        assert_eq!(package.provides.into_iter().collect::<Vec<_>>(), ["foo"]);
+        assert_eq!(
+            package.replaces.into_iter().collect::<Vec<_>>(),
+            ["bar", "foo-old"]
+        );
+        assert!(get_components("foo,foo,1.0,foo.src.rpm,42,\t").is_ok());
+    }
 
     #[test]
     fn test() {
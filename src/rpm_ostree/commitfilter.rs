@@ -0,0 +1,290 @@
+//! Path filtering applied through the commit modifier while generating a commit
+
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use ostree_ext::{gio, ostree};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::pkgdb::pattern::glob_to_regex;
+use crate::rpm_ostree::reproducible::ReproducibleFilter;
+
+/// Caches, logs and temporary files which should not end up in an image.
+pub(crate) const DEFAULT_EXCLUDES: &[&str] = &[
+    "/tmp/*",
+    "/var/tmp/*",
+    "/var/cache/*",
+    "/var/log/*",
+    "/var/lib/apt/lists/*",
+    "/var/lib/pacman/sync/*",
+    "/**/.git",
+];
+
+/// Include and exclude globs for the paths of the commit, e.g.
+///
+/// ```toml
+/// default-excludes = true
+/// exclude = ["/usr/share/doc/**"]
+/// include = ["/var/cache/fontconfig"]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct PathFilterConfig {
+    // Also exclude the `DEFAULT_EXCLUDES`
+    #[serde(default)]
+    pub(crate) default_excludes: bool,
+    #[serde(default)]
+    pub(crate) exclude: Vec<String>,
+    // Paths kept even though they match an exclude glob
+    #[serde(default)]
+    pub(crate) include: Vec<String>,
+}
+
+impl PathFilterConfig {
+    pub(crate) fn new_from_toml(path: &Utf8Path) -> Result<Self> {
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("Reading {}", path))?;
+        toml::from_str(&contents).with_context(|| format!("Parsing {}", path))
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        !self.default_excludes && self.exclude.is_empty()
+    }
+}
+
+/// Number of entries and bytes of regular files left out because of an exclude glob.
+#[derive(Debug, Default)]
+struct Excluded {
+    entries: u64,
+    size: u64,
+}
+
+/// Leaves out paths matching an exclude glob, unless they also match an include glob.
+///
+/// An excluded directory is left out with all its content, so `/var/cache` drops the directory itself while
+/// `/var/cache/*` only drops its content.
+#[derive(Debug)]
+pub(crate) struct PathFilter {
+    excludes: Vec<(String, Regex)>,
+    includes: Vec<Regex>,
+    excluded: Mutex<BTreeMap<String, Excluded>>,
+}
+
+impl PathFilter {
+    pub(crate) fn new(config: &PathFilterConfig) -> Result<Self> {
+        let defaults = DEFAULT_EXCLUDES
+            .iter()
+            .filter(|_| config.default_excludes)
+            .map(|glob| glob.to_string());
+        let excludes = config
+            .exclude
+            .iter()
+            .cloned()
+            .chain(defaults)
+            .map(|glob| {
+                let regex = glob_to_regex(&glob)?;
+                Ok((glob, regex))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let includes = config
+            .include
+            .iter()
+            .map(|glob| glob_to_regex(glob))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(PathFilter {
+            excludes,
+            includes,
+            excluded: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Whether to leave out `path`, recording it for the summary.
+    fn exclude(&self, path: &Utf8Path, info: &gio::FileInfo) -> bool {
+        let Some((glob, _regex)) = self
+            .excludes
+            .iter()
+            .find(|(_glob, regex)| regex.is_match(path.as_str()))
+        else {
+            return false;
+        };
+        if self
+            .includes
+            .iter()
+            .any(|regex| regex.is_match(path.as_str()))
+        {
+            return false;
+        }
+        tracing::debug!("Excluding {} ({})", path, glob);
+        let mut excluded = self.excluded.lock().unwrap();
+        let excluded = excluded.entry(glob.clone()).or_default();
+        excluded.entries += 1;
+        if info.file_type() == gio::FileType::Regular {
+            excluded.size += info.size() as u64;
+        }
+        true
+    }
+
+    /// Print how much was excluded by each glob.
+    pub(crate) fn print_summary(&self) {
+        let excluded = self.excluded.lock().unwrap();
+        if excluded.is_empty() {
+            println!("No paths excluded from the commit");
+            return;
+        }
+        println!("Excluded from the commit:");
+        for (glob, excluded) in excluded.iter() {
+            println!(
+                "  {}: {} entries, {} bytes in regular files",
+                glob, excluded.entries, excluded.size
+            );
+        }
+    }
+}
+
+/// The filter callback of the commit modifier, combining path filtering and reproducibility.
+#[derive(Debug, Default)]
+pub(crate) struct CommitFilter {
+    pub(crate) paths: Option<PathFilter>,
+    pub(crate) reproducible: Option<ReproducibleFilter>,
+    // The toplevel directory currently being committed, as filter paths are relative to it
+    toplevel: Mutex<String>,
+}
+
+impl CommitFilter {
+    pub(crate) fn new(paths: Option<PathFilter>, reproducible: Option<ReproducibleFilter>) -> Self {
+        CommitFilter {
+            paths,
+            reproducible,
+            toplevel: Mutex::default(),
+        }
+    }
+
+    /// Set the toplevel directory whose content is committed next.
+    pub(crate) fn set_toplevel(&self, name: &str) {
+        *self.toplevel.lock().unwrap() = name.to_string();
+    }
+
+    /// Whether to leave out a toplevel entry, which is committed without going through the modifier.
+    pub(crate) fn exclude_toplevel(&self, name: &str, file_type: gio::FileType) -> bool {
+        self.exclude_path(&Utf8Path::new("/").join(name), file_type)
+    }
+
+    /// Whether to leave out `path`, for content that ends up in the image without going through the modifier.
+    pub(crate) fn exclude_path(&self, path: &Utf8Path, file_type: gio::FileType) -> bool {
+        let info = gio::FileInfo::new();
+        info.set_file_type(file_type);
+        self.skip(path, &info)
+    }
+
+    /// Whether to leave out `path`, because it matches an exclude glob or the reproducibility filter.
+    fn skip(&self, path: &Utf8Path, info: &gio::FileInfo) -> bool {
+        self.paths.as_ref().is_some_and(|f| f.exclude(path, info))
+            || self
+                .reproducible
+                .as_ref()
+                .is_some_and(|f| f.skip(path, info))
+    }
+
+    fn filter(&self, path: &str, info: &gio::FileInfo) -> ostree::RepoCommitFilterResult {
+        let mut toplevel_path: Utf8PathBuf =
+            Utf8Path::new("/").join(self.toplevel.lock().unwrap().as_str());
+        // The toplevel directory itself is passed as "/", which must not gain a trailing slash
+        let relpath = path.trim_start_matches('/');
+        if !relpath.is_empty() {
+            toplevel_path.push(relpath);
+        }
+        if self.skip(&toplevel_path, info) {
+            ostree::RepoCommitFilterResult::Skip
+        } else {
+            ostree::RepoCommitFilterResult::Allow
+        }
+    }
+
+    /// Create a commit modifier applying the filter.
+    pub(crate) fn modifier(self: &Arc<Self>) -> ostree::RepoCommitModifier {
+        let filter = Arc::clone(self);
+        ostree::RepoCommitModifier::new(
+            ostree::RepoCommitModifierFlags::empty(),
+            Some(Box::new(
+                move |_repo: &ostree::Repo, path: &str, info: &gio::FileInfo| {
+                    filter.filter(path, info)
+                },
+            )),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regular_file(size: i64) -> gio::FileInfo {
+        let info = gio::FileInfo::new();
+        info.set_file_type(gio::FileType::Regular);
+        info.set_size(size);
+        info
+    }
+
+    #[test]
+    fn test_path_filter() {
+        let config: PathFilterConfig = toml::from_str(
+            r#"
+            default-excludes = true
+            exclude = ["/usr/share/doc/**"]
+            include = ["/var/cache/fontconfig"]
+            "#,
+        )
+        .unwrap();
+        let filter = PathFilter::new(&config).unwrap();
+        let info = regular_file(10);
+        for path in [
+            "/usr/share/doc/a/README",
+            "/var/cache/dnf",
+            "/tmp/x",
+            "/src/.git",
+        ] {
+            assert!(filter.exclude(Utf8Path::new(path), &info), "{path}");
+        }
+        for path in [
+            "/usr/share/docs",
+            "/var/cache",
+            "/var/cache/fontconfig",
+            "/tmp",
+            "/usr/bin/git",
+        ] {
+            assert!(!filter.exclude(Utf8Path::new(path), &info), "{path}");
+        }
+        let excluded = filter.excluded.lock().unwrap();
+        assert_eq!(excluded["/usr/share/doc/**"].entries, 1);
+        assert_eq!(excluded["/**/.git"].size, 10);
+    }
+
+    #[test]
+    fn test_commit_filter_toplevel_root() {
+        let config = PathFilterConfig {
+            default_excludes: true,
+            ..Default::default()
+        };
+        let filter = CommitFilter::new(Some(PathFilter::new(&config).unwrap()), None);
+        filter.set_toplevel("tmp");
+        let dir = gio::FileInfo::new();
+        dir.set_file_type(gio::FileType::Directory);
+        assert_eq!(
+            filter.filter("/", &dir),
+            ostree::RepoCommitFilterResult::Allow
+        );
+        assert_eq!(
+            filter.filter("", &dir),
+            ostree::RepoCommitFilterResult::Allow
+        );
+        assert_eq!(
+            filter.filter("/foo", &regular_file(1)),
+            ostree::RepoCommitFilterResult::Skip
+        );
+    }
+}
@@ -4,15 +4,18 @@
 
 use std::fs::File;
 use std::io::Write;
-use std::os::fd::{AsFd, AsRawFd};
+use std::num::NonZeroU32;
+use std::os::fd::AsRawFd;
 use std::process::Command;
+use std::sync::Arc;
 
 use anyhow::{Context, Result, anyhow};
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use cap_std::fs::{Dir, MetadataExt};
 use clap::Parser;
 use fn_error_context::context;
 use ostree::gio;
+use ostree::gio::prelude::OutputStreamExtManual;
 use ostree_ext::glib::prelude::*;
 use ostree_ext::oci_spec::image::ImageConfiguration;
 use ostree_ext::ostree::MutableTree;
@@ -20,12 +23,27 @@ use ostree_ext::{container as ostree_container, glib};
 use ostree_ext::{oci_spec, ostree};
 
 use crate::rpm_ostree::cmdutils::CommandRunExt;
-use crate::rpm_ostree::containers_storage::Mount;
+use crate::rpm_ostree::commitfilter::{CommitFilter, PathFilter, PathFilterConfig};
+use crate::rpm_ostree::container::COMPOSEFS_DIGEST_KEY;
+use crate::rpm_ostree::containers_storage::{self, Mount};
+use crate::rpm_ostree::delta::generate_static_delta;
+use crate::rpm_ostree::reproducible::ReproducibleFilter;
+use crate::rpm_ostree::selinux::{load_policy, selinux_xattrs, verify_selinux_labels};
+use crate::rpm_ostree::tmpfiles::{AUTOVAR_DIR, AUTOVAR_NAME, convert_var_to_tmpfiles_d};
 
 const SYSROOT: &str = "sysroot";
 const USR: &str = "usr";
 const ETC: &str = "etc";
 const USR_ETC: &str = "usr/etc";
+const VAR: &str = "var";
+/// Version of the composefs image format the digest in the commit metadata is computed for
+const COMPOSEFS_FORMAT_VERSION: u32 = 0;
+/// Commit metadata key of the version
+const VERSION_KEY: &str = "version";
+/// Commit metadata key of the content hash, see [`content_hash`]
+const INPUTHASH_KEY: &str = "rpmostree.inputhash";
+/// Commit metadata keys generated from the options and the content, which can't be set as metadata strings
+const GENERATED_METADATA_KEYS: &[&str] = &[VERSION_KEY, INPUTHASH_KEY, COMPOSEFS_DIGEST_KEY];
 
 #[derive(clap::ValueEnum, Clone, Debug)]
 enum OutputFormat {
@@ -50,6 +68,19 @@ impl From<OutputFormat> for ostree_container::Transport {
     }
 }
 
+/// What to do with files created by container runtimes at the toplevel of the rootfs.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum ContainerArtifacts {
+    /// Leave them out of the commit
+    #[default]
+    Drop,
+    /// Commit them like any other file
+    Keep,
+}
+
+/// Files container runtimes create at the toplevel, which are meaningless on a booted system.
+const CONTAINER_ARTIFACTS: &[&str] = &[".dockerenv", ".containerenv"];
+
 /// Generate an OSTree repo and commit from an input rootfs.
 #[derive(Debug, Parser)]
 pub(crate) struct BuildChunkedOCIOpts {
@@ -68,6 +99,87 @@ pub(crate) struct BuildChunkedOCIOpts {
     /// Write the commit id to this file after successfully creating the OSTree repository
     #[clap(long, required = true)]
     output_commitid: Option<Utf8PathBuf>,
+
+    /// Replace the content of /var with tmpfiles.d entries, as ostree only populates /var on the first deployment
+    #[clap(long)]
+    var_to_tmpfiles: bool,
+
+    /// Version of the commit, stored in the `version` metadata key
+    #[clap(long)]
+    commit_version: Option<String>,
+
+    /// Append a KEY=VALUE string to the commit metadata. Generated keys (`version`, `rpmostree.inputhash` and
+    /// `ostree.composefs.digest.v0`) are rejected, use the dedicated options instead.
+    #[clap(name = "add-metadata-string", long)]
+    metadata_strings: Vec<String>,
+
+    /// Write the commit to this ref. If the output repository already has the ref, its commit becomes the parent.
+    #[clap(name = "ref", long)]
+    ostree_ref: Option<String>,
+
+    /// Only keep the last N commits of the ref and prune everything else from the repository. ostree prunes all refs
+    /// to the same depth, so this is refused for repositories with other refs.
+    #[clap(long, requires = "ref")]
+    keep_last: Option<NonZeroU32>,
+
+    /// Generate a static delta from the parent commit. This happens before pruning, so it also works with
+    /// `--keep-last 1`, which prunes the parent.
+    #[clap(long, requires = "ref")]
+    static_delta: bool,
+
+    /// Generate a reproducible commit: use SOURCE_DATE_EPOCH as creation time and skip or canonicalize files known
+    /// to differ between builds. ostree does not record file mtimes, so identical inputs yield an identical commit.
+    #[clap(long)]
+    reproducible: bool,
+
+    /// Add a GLOB=ACTION reproducibility filter, with ACTION being one of skip, empty or pyc
+    #[clap(name = "reproducible-filter", long, requires = "reproducible")]
+    reproducible_filters: Vec<String>,
+
+    /// Do not apply the default reproducibility filters
+    #[clap(long, requires = "reproducible")]
+    no_default_reproducible_filters: bool,
+
+    /// TOML file with `include` and `exclude` globs for the paths of the commit
+    #[clap(long)]
+    path_filter: Option<Utf8PathBuf>,
+
+    /// Leave paths matching this glob out of the commit
+    #[clap(long)]
+    exclude: Vec<String>,
+
+    /// Keep paths matching this glob, even if they match an exclude glob
+    #[clap(long)]
+    include: Vec<String>,
+
+    /// Leave out caches, logs, temporary files and .git directories
+    #[clap(long)]
+    default_excludes: bool,
+
+    /// What to do with toplevel files created by container runtimes, like `/.dockerenv`
+    #[clap(long, value_enum, default_value_t)]
+    container_artifacts: ContainerArtifacts,
+
+    /// Label using the SELinux policy of this root instead of the one in the rootfs
+    #[clap(long, conflicts_with = "no_selinux")]
+    selinux_policy_root: Option<Utf8PathBuf>,
+
+    /// Do not label the commit, e.g. for distributions not using SELinux
+    #[clap(long)]
+    no_selinux: bool,
+
+    /// Fail if any path of the commit has no SELinux label, listing the unlabeled paths
+    #[clap(long, conflicts_with = "no_selinux")]
+    verify_selinux_labels: bool,
+
+    /// Add the composefs digest of the commit to its metadata (`ostree.composefs.digest.v0`)
+    #[clap(long)]
+    composefs: bool,
+
+    /// Enable fs-verity on the objects written to the repository, failing if the filesystem does not support it. This
+    /// is stored in the repository configuration, so it stays enabled for later runs on the same repository.
+    #[clap(long)]
+    fsverity: bool,
 }
 
 impl BuildChunkedOCIOpts {
@@ -83,13 +195,11 @@ impl BuildChunkedOCIOpts {
             FileSource::Rootfs(rootfs)
         } else {
             let image = self.from.as_deref().unwrap();
-            // TODO: Fix running this inside unprivileged podman too. We'll likely need
-            // to refactor things into a two-step process where we do the mount+ostree repo commit
-            // in a subprocess that has the "unshare", and then the secondary main process
-            // just reads/operates on that.
+            // Mounting needs privileges, so rootless (or in an unprivileged container) this re-executes the whole
+            // process in a suitable user namespace and doesn't return.
             // Note that this would all be a lot saner with a composefs-native container storage
             // as we could cleanly operate on that, asking c/storage to synthesize one for us.
-            // crate::containers_storage::reexec_if_needed()?;
+            containers_storage::reexec_if_needed()?;
             FileSource::Podman(Mount::new_for_image(image)?)
         };
         let rootfs = match &rootfs_source {
@@ -123,22 +233,134 @@ impl BuildChunkedOCIOpts {
             .as_deref()
             .map(chrono::DateTime::parse_from_rfc3339)
             .transpose()?;
+        let creation_timestamp = if self.reproducible {
+            match crate::util::get_source_date_epoch() {
+                Some(epoch) => Some(
+                    chrono::DateTime::from_timestamp(epoch.try_into()?, 0)
+                        .ok_or_else(|| anyhow!("Invalid SOURCE_DATE_EPOCH {}", epoch))?
+                        .fixed_offset(),
+                ),
+                None => {
+                    tracing::warn!(
+                        "SOURCE_DATE_EPOCH is not set, the commit time depends on the input"
+                    );
+                    creation_timestamp
+                }
+            }
+        } else {
+            creation_timestamp
+        };
 
-        let repo = ostree::Repo::create_at(
-            libc::AT_FDCWD,
-            self.output.as_str(),
-            ostree::RepoMode::BareUser,
-            None,
-            gio::Cancellable::NONE,
-        )?;
+        // Appending to an existing repository reuses its objects, so only changed content gets written
+        let repo = if self.output.join("config").try_exists()? {
+            println!("Using existing repository {}", self.output);
+            ostree::Repo::open_at(libc::AT_FDCWD, self.output.as_str(), gio::Cancellable::NONE)?
+        } else {
+            ostree::Repo::create_at(
+                libc::AT_FDCWD,
+                self.output.as_str(),
+                ostree::RepoMode::BareUser,
+                None,
+                gio::Cancellable::NONE,
+            )?
+        };
+        if self.keep_last.is_some() {
+            // Pruning keeps the same number of commits for every ref, which would cut the history of other refs
+            let ostree_ref = self.ostree_ref.as_deref().unwrap();
+            let mut other_refs = repo
+                .list_refs(None, gio::Cancellable::NONE)?
+                .into_keys()
+                .filter(|name| name != ostree_ref)
+                .collect::<Vec<_>>();
+            if !other_refs.is_empty() {
+                other_refs.sort();
+                anyhow::bail!(
+                    "Refusing --keep-last, as it would also prune the history of the other refs in {}: {}",
+                    self.output,
+                    other_refs.join(", ")
+                );
+            }
+        }
+        if self.fsverity {
+            let config = repo.copy_config();
+            if !config
+                .boolean("ex-integrity", "fsverity")
+                .unwrap_or_default()
+            {
+                config.set_boolean("ex-integrity", "fsverity", true);
+                repo.write_config(&config)?;
+                repo.reload_config(gio::Cancellable::NONE)?;
+                println!(
+                    "Enabled fs-verity in the configuration of {}, also for later runs",
+                    self.output
+                );
+            }
+        }
 
         println!("Generating commit...");
-        // It's only the tests that override
-        let modifier =
-            ostree::RepoCommitModifier::new(ostree::RepoCommitModifierFlags::empty(), None);
+        let reproducible = self
+            .reproducible
+            .then(|| {
+                ReproducibleFilter::new(
+                    &self.reproducible_filters,
+                    !self.no_default_reproducible_filters,
+                )
+            })
+            .transpose()?;
+        let mut path_filter_config = match &self.path_filter {
+            Some(path) => PathFilterConfig::new_from_toml(path)?,
+            None => PathFilterConfig::default(),
+        };
+        path_filter_config.default_excludes |= self.default_excludes;
+        path_filter_config
+            .exclude
+            .extend(self.exclude.iter().cloned());
+        path_filter_config
+            .include
+            .extend(self.include.iter().cloned());
+        let path_filter = (!path_filter_config.is_empty())
+            .then(|| PathFilter::new(&path_filter_config))
+            .transpose()?;
+        let filter = Arc::new(CommitFilter::new(path_filter, reproducible));
+        let modifier = if filter.paths.is_some() || filter.reproducible.is_some() {
+            filter.modifier()
+        } else {
+            // It's only the tests that override
+            ostree::RepoCommitModifier::new(ostree::RepoCommitModifierFlags::empty(), None)
+        };
         // Process the filesystem, generating an ostree commit
-        let commitid =
-            generate_commit_from_rootfs(&repo, &rootfs, modifier, creation_timestamp.as_ref())?;
+        let metadata_strings = self
+            .metadata_strings
+            .iter()
+            .map(|m| {
+                let (k, v) = m
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Missing '=' in metadata string {}", m))?;
+                if GENERATED_METADATA_KEYS.contains(&k) {
+                    anyhow::bail!("Metadata key {} is generated and can't be set as string", k);
+                }
+                Ok((k.to_string(), v.to_string()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let commit_opts = CommitOptions {
+            creation_time: creation_timestamp.as_ref(),
+            var_to_tmpfiles: self.var_to_tmpfiles,
+            version: self.commit_version.as_deref(),
+            metadata_strings,
+            ostree_ref: self.ostree_ref.as_deref(),
+            filter: &filter,
+            container_artifacts: self.container_artifacts,
+            selinux_policy_root: self.selinux_policy_root.as_deref(),
+            no_selinux: self.no_selinux,
+            composefs: self.composefs,
+        };
+        let commitid = generate_commit_from_rootfs(&repo, &rootfs, modifier, &commit_opts)?;
+        if self.verify_selinux_labels {
+            verify_selinux_labels(&repo, &commitid)?;
+        }
+        if let Some(path_filter) = &filter.paths {
+            path_filter.print_summary();
+        }
 
         drop(rootfs);
         match rootfs_source {
@@ -151,6 +373,36 @@ impl BuildChunkedOCIOpts {
         println!("Commit generated successfully. Commit ID:");
         println!("{}", commitid);
 
+        if self.static_delta {
+            let (commit, _state) = repo.load_commit(&commitid)?;
+            match ostree::commit_get_parent(&commit) {
+                Some(parent) => {
+                    let delta_size = generate_static_delta(
+                        &self.output,
+                        &repo,
+                        Some(parent.as_str()),
+                        &commitid,
+                    )?;
+                    println!("Static delta size: {}", delta_size);
+                }
+                None => println!("Commit has no parent, not generating a static delta"),
+            }
+        }
+
+        if let Some(keep_last) = self.keep_last {
+            // The depth counts the parents traversed from each ref, so a depth of 0 only keeps the ref itself
+            let depth = i32::try_from(keep_last.get() - 1).context("--keep-last is too large")?;
+            let (_total, pruned, freed) = repo.prune(
+                ostree::RepoPruneFlags::REFS_ONLY,
+                depth,
+                gio::Cancellable::NONE,
+            )?;
+            println!(
+                "Pruned {} objects ({} bytes), keeping the last {} commits",
+                pruned, freed, keep_last
+            );
+        }
+
         if let Some(commit_outpath) = self.output_commitid {
             let mut file = File::create(commit_outpath)?;
             write!(&mut file, "{}", commitid)?;
@@ -160,23 +412,18 @@ impl BuildChunkedOCIOpts {
     }
 }
 
-fn label_to_xattrs(label: Option<&str>) -> Option<glib::Variant> {
-    let xattrs = label.map(|label| {
-        let mut label: Vec<_> = label.to_owned().into();
-        label.push(0);
-        vec![(c"security.selinux".to_bytes_with_nul(), label)]
-    });
-    xattrs.map(|x| x.to_variant())
-}
-
-fn create_root_dirmeta(root: &Dir, policy: &ostree::SePolicy) -> Result<glib::Variant> {
+/// Create the dirmeta of a directory owned by root, with `path` being its absolute path in the commit.
+fn create_dirmeta(
+    dir: &Dir,
+    path: &str,
+    policy: Option<&ostree::SePolicy>,
+) -> Result<glib::Variant> {
     let finfo = gio::FileInfo::new();
-    let meta = root.dir_metadata()?;
+    let meta = dir.dir_metadata()?;
     finfo.set_attribute_uint32("unix::uid", 0);
     finfo.set_attribute_uint32("unix::gid", 0);
     finfo.set_attribute_uint32("unix::mode", libc::S_IFDIR | meta.mode());
-    let label = policy.label("/", 0o777 | libc::S_IFDIR, gio::Cancellable::NONE)?;
-    let xattrs = label_to_xattrs(label.as_deref());
+    let xattrs = selinux_xattrs(policy, path, 0o777 | libc::S_IFDIR)?;
     let r = ostree::create_directory_metadata(&finfo, xattrs.as_ref());
     Ok(r)
 }
@@ -245,21 +492,157 @@ fn postprocess_mtree(repo: &ostree::Repo, rootfs: &ostree::MutableTree) -> Resul
     Ok(())
 }
 
+/// Look up the directory at `path` relative to the root, creating missing directories.
+fn mtree_ensure_dir(root_mtree: &MutableTree, path: &str) -> Result<MutableTree> {
+    let mut dir = root_mtree.clone();
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        dir = match mtree_lookup(&dir, component)? {
+            Some(entry) => entry.require_dir().context(component.to_string())?,
+            None => {
+                // New directories take over the metadata of their parent
+                let child = dir.ensure_dir(component)?;
+                child.set_metadata_checksum(&dir.metadata_checksum());
+                child
+            }
+        };
+    }
+    Ok(dir)
+}
+
+/// Commit the files skipped by the reproducibility filter with canonicalized content.
+#[context("Writing canonicalized files")]
+fn write_reproducible_rewrites(
+    repo: &ostree::Repo,
+    rootfs: &Dir,
+    root_mtree: &MutableTree,
+    policy: Option<&ostree::SePolicy>,
+    filter: &ReproducibleFilter,
+) -> Result<()> {
+    let cancellable = gio::Cancellable::NONE;
+    for (path, action) in filter.take_rewrites() {
+        let relpath = path.as_str().trim_start_matches('/');
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            continue;
+        };
+        // Ownership and permissions are kept, only the content changes
+        let meta = rootfs
+            .symlink_metadata(relpath)
+            .with_context(|| format!("Querying {path}"))?;
+        let content = rootfs
+            .read(relpath)
+            .with_context(|| format!("Reading {path}"))?;
+        let content = action.rewrite(content);
+        let mode = libc::S_IFREG | (meta.mode() & 0o7777);
+        let xattrs = selinux_xattrs(policy, path.as_str(), mode)?;
+        let checksum = repo
+            .write_regfile_inline(
+                None,
+                meta.uid(),
+                meta.gid(),
+                mode,
+                xattrs.as_ref(),
+                &content,
+                cancellable,
+            )
+            .with_context(|| format!("Writing {path}"))?;
+        mtree_ensure_dir(root_mtree, parent.as_str())?.replace_file(name, &checksum)?;
+    }
+    Ok(())
+}
+
+/// Add the tmpfiles.d configuration generated from /var to the commit.
+#[context("Writing {AUTOVAR_NAME}")]
+fn write_autovar(
+    repo: &ostree::Repo,
+    root_mtree: &MutableTree,
+    policy: Option<&ostree::SePolicy>,
+    contents: &str,
+) -> Result<()> {
+    let cancellable = gio::Cancellable::NONE;
+    let dir = mtree_ensure_dir(root_mtree, AUTOVAR_DIR)?;
+    let path = format!("/{AUTOVAR_DIR}/{AUTOVAR_NAME}");
+    let xattrs = selinux_xattrs(policy, &path, 0o644 | libc::S_IFREG)?;
+    let checksum = repo.write_regfile_inline(
+        None,
+        0,
+        0,
+        libc::S_IFREG | 0o644,
+        xattrs.as_ref(),
+        contents.as_bytes(),
+        cancellable,
+    )?;
+    dir.replace_file(AUTOVAR_NAME, &checksum)?;
+    Ok(())
+}
+
+/// Settings for the commit generated from a rootfs, besides the content itself.
+struct CommitOptions<'a> {
+    creation_time: Option<&'a chrono::DateTime<chrono::FixedOffset>>,
+    var_to_tmpfiles: bool,
+    version: Option<&'a str>,
+    metadata_strings: Vec<(String, String)>,
+    ostree_ref: Option<&'a str>,
+    // The filter of the commit modifier
+    filter: &'a CommitFilter,
+    container_artifacts: ContainerArtifacts,
+    // Root to load the SELinux policy from instead of the rootfs
+    selinux_policy_root: Option<&'a Utf8Path>,
+    no_selinux: bool,
+    composefs: bool,
+}
+
+/// A hash of the content of the commit, which unlike the commit checksum does not depend on the creation time.
+/// This allows for cheap change detection, just like `rpmostree.inputhash` does in rpm-ostree.
+fn content_hash(root_mtree: &MutableTree) -> String {
+    let mut checksum = glib::Checksum::new(glib::ChecksumType::Sha256).unwrap();
+    checksum.update(root_mtree.contents_checksum().as_bytes());
+    checksum.update(root_mtree.metadata_checksum().as_bytes());
+    checksum.string().unwrap().into()
+}
+
+fn commit_metadata(
+    repo: &ostree::Repo,
+    opts: &CommitOptions,
+    root_mtree: &MutableTree,
+    ostree_root: &ostree::RepoFile,
+) -> Result<glib::Variant> {
+    let metadata = glib::VariantDict::new(None);
+    if let Some(version) = opts.version {
+        metadata.insert(VERSION_KEY, version);
+    }
+    for (key, value) in &opts.metadata_strings {
+        metadata.insert(key, value.as_str());
+    }
+    metadata.insert(INPUTHASH_KEY, content_hash(root_mtree).as_str());
+    if opts.composefs {
+        // The digest covers the fs-verity digests of all content objects, so it pins the content of the image
+        repo.commit_add_composefs_metadata(
+            COMPOSEFS_FORMAT_VERSION,
+            &metadata,
+            ostree_root,
+            gio::Cancellable::NONE,
+        )
+        .context("Computing composefs digest")?;
+    }
+    Ok(metadata.end())
+}
+
 #[context("Generating commit from rootfs")]
 fn generate_commit_from_rootfs(
     repo: &ostree::Repo,
     rootfs: &Dir,
     modifier: ostree::RepoCommitModifier,
-    creation_time: Option<&chrono::DateTime<chrono::FixedOffset>>,
+    opts: &CommitOptions,
 ) -> Result<String> {
     let root_mtree = ostree::MutableTree::new();
     let cancellable = gio::Cancellable::NONE;
     let tx = repo.auto_transaction(cancellable)?;
 
-    let policy = ostree::SePolicy::new_at(rootfs.as_fd().as_raw_fd(), cancellable)?;
-    modifier.set_sepolicy(Some(&policy));
+    let policy = load_policy(rootfs, opts.selinux_policy_root, opts.no_selinux)?;
+    let policy = policy.as_ref();
+    modifier.set_sepolicy(policy);
 
-    let root_dirmeta = create_root_dirmeta(rootfs, &policy)?;
+    let root_dirmeta = create_dirmeta(rootfs, "/", policy)?;
     let root_metachecksum = repo
         .write_metadata(
             ostree::ObjectType::DirMeta,
@@ -275,13 +658,42 @@ fn generate_commit_from_rootfs(
         let name = ent.file_name()?;
 
         let ftype = ent.file_type()?;
+        let gio_ftype = if ftype.is_dir() {
+            gio::FileType::Directory
+        } else if ftype.is_symlink() {
+            gio::FileType::SymbolicLink
+        } else if ftype.is_file() {
+            gio::FileType::Regular
+        } else {
+            // Sockets, FIFOs and device nodes are created at runtime, ostree can't store them anyway
+            tracing::warn!("Skipping special file /{name} at toplevel");
+            continue;
+        };
+        if opts.filter.exclude_toplevel(&name, gio_ftype) {
+            continue;
+        }
+        if opts.container_artifacts == ContainerArtifacts::Drop
+            && CONTAINER_ARTIFACTS.contains(&name.as_str())
+        {
+            println!("Dropping container runtime artifact /{name}");
+            continue;
+        }
         // Skip the contents of the sysroot
         if ftype.is_dir() && name == SYSROOT {
             let child_mtree = root_mtree.ensure_dir(&name)?;
             child_mtree.set_metadata_checksum(&root_metachecksum.to_hex());
+        } else if ftype.is_dir() && name == VAR && opts.var_to_tmpfiles {
+            // Only keep /var itself, its content is recreated by systemd-tmpfiles
+            let var_dirmeta = create_dirmeta(&ent.open_dir()?, "/var", policy)?;
+            let var_metachecksum = repo
+                .write_metadata(ostree::ObjectType::DirMeta, None, &var_dirmeta, cancellable)
+                .context("Writing /var dirmeta")?;
+            let child_mtree = root_mtree.ensure_dir(&name)?;
+            child_mtree.set_metadata_checksum(&var_metachecksum.to_hex());
         } else if ftype.is_dir() {
             let child_mtree = root_mtree.ensure_dir(&name)?;
             let child = ent.open_dir()?;
+            opts.filter.set_toplevel(&name);
             repo.write_dfd_to_mtree(
                 child.as_raw_fd(),
                 ".",
@@ -297,37 +709,80 @@ fn generate_commit_from_rootfs(
                 .try_into()?;
             // Label lookups need to be absolute
             let selabel_path = format!("/{name}");
-            let label = policy.label(selabel_path.as_str(), 0o777 | libc::S_IFLNK, cancellable)?;
-            let xattrs = label_to_xattrs(label.as_deref());
+            let xattrs = selinux_xattrs(policy, selabel_path.as_str(), 0o777 | libc::S_IFLNK)?;
             let link_checksum = repo
                 .write_symlink(None, 0, 0, xattrs.as_ref(), contents.as_str(), cancellable)
                 .with_context(|| format!("Processing symlink {selabel_path}"))?;
             root_mtree.replace_file(&name, &link_checksum)?;
         } else {
-            // Yes we could support this but it's a surprising amount of typing
-            anyhow::bail!("Unsupported regular file {name} at toplevel");
+            // Regular file, special files were skipped above
+            let meta = ent.metadata()?;
+            let mode = libc::S_IFREG | (meta.mode() & 0o7777);
+            // Label lookups need to be absolute
+            let selabel_path = format!("/{name}");
+            let xattrs = selinux_xattrs(policy, selabel_path.as_str(), mode)?;
+            let mut file = rootfs
+                .open(&name)
+                .with_context(|| format!("Opening {name}"))?;
+            // Nothing limits the size of files dropped at the toplevel, so stream them instead of reading them whole
+            let writer = repo.write_regfile(
+                None,
+                meta.uid(),
+                meta.gid(),
+                mode,
+                meta.len(),
+                xattrs.as_ref(),
+            )?;
+            let mut output = writer.clone().into_write();
+            std::io::copy(&mut file, &mut output)
+                .with_context(|| format!("Processing file {selabel_path}"))?;
+            output.flush()?;
+            let file_checksum = writer
+                .finish(cancellable)
+                .with_context(|| format!("Processing file {selabel_path}"))?;
+            root_mtree.replace_file(&name, &file_checksum)?;
         }
     }
 
+    if let Some(filter) = &opts.filter.reproducible {
+        write_reproducible_rewrites(repo, rootfs, &root_mtree, policy, filter)?;
+    }
+
+    if opts.var_to_tmpfiles && rootfs.try_exists(VAR)? {
+        let contents = convert_var_to_tmpfiles_d(rootfs, opts.filter)?;
+        write_autovar(repo, &root_mtree, policy, &contents)?;
+    }
+
     postprocess_mtree(repo, &root_mtree)?;
 
     let ostree_root = repo.write_mtree(&root_mtree, cancellable)?;
     let ostree_root = ostree_root.downcast_ref::<ostree::RepoFile>().unwrap();
-    let creation_time: u64 = creation_time
-        .as_ref()
+    let creation_time: u64 = opts
+        .creation_time
         .map(|t| t.timestamp())
         .unwrap_or_default()
         .try_into()
         .context("Parsing creation time")?;
+    let metadata = commit_metadata(repo, opts, &root_mtree, ostree_root)?;
+    let parent = match opts.ostree_ref {
+        Some(ostree_ref) => repo.resolve_rev(ostree_ref, true)?,
+        None => None,
+    };
+    if let Some(parent) = &parent {
+        println!("Using {} as parent commit", parent);
+    }
     let commit = repo.write_commit_with_time(
+        parent.as_deref(),
         None,
         None,
-        None,
-        None,
+        Some(&metadata),
         ostree_root,
         creation_time,
         cancellable,
     )?;
+    if let Some(ostree_ref) = opts.ostree_ref {
+        repo.transaction_set_ref(None, ostree_ref, Some(commit.as_str()));
+    }
 
     tx.commit(cancellable)?;
     Ok(commit.into())
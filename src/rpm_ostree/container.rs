@@ -26,11 +26,11 @@ use ostree_ext::objectsource::{
 use ostree_ext::oci_spec::image::{Arch, Os, PlatformBuilder};
 use ostree_ext::ostree::Repo;
 use ostree_ext::prelude::*;
-use ostree_ext::{gio, oci_spec, ostree};
+use ostree_ext::{gio, glib, oci_spec, ostree};
 
-use crate::pkgdb::PackageIndex;
-use crate::rpm_ostree::fsutil::{self, FileHelpers, ResolvedOstreePaths};
-use crate::util::get_buildtime;
+use crate::chunking::volatility::Volatility;
+use crate::pkgdb::{ChangeIdMode, PackageIndex};
+use crate::rpm_ostree::fsutil::{self, FileHelpers, PathTranslation, ResolvedOstreePaths};
 
 #[derive(Debug, Parser)]
 pub struct ContainerEncapsulateOpts {
@@ -198,7 +198,10 @@ fn build_fs_mapping_recurse(
     Ok(())
 }
 
-async fn compare_builds(old_build: &str, new_build: &str) -> Result<()> {
+/// Print the layer differences between two builds.
+///
+/// Returns the number and total size of the layers added in the new build, which is what a client has to fetch.
+pub(crate) async fn compare_builds(old_build: &str, new_build: &str) -> Result<(usize, u64)> {
     let proxy = containers_image_proxy::ImageProxy::new().await?;
     let oi_old = proxy.open_image(old_build).await?;
     let (_, manifest_old) = proxy.fetch_manifest(&oi_old).await?;
@@ -206,7 +209,12 @@ async fn compare_builds(old_build: &str, new_build: &str) -> Result<()> {
     let (_, new_manifest) = proxy.fetch_manifest(&oi_now).await?;
     let diff = ostree_ext::container::ManifestDiff::new(&manifest_old, &new_manifest);
     diff.print();
-    Ok(())
+    let added_size = diff
+        .added
+        .iter()
+        .map(|layer| u64::try_from(layer.size()).unwrap_or_default())
+        .sum();
+    Ok((diff.added.len(), added_size))
 }
 
 pub fn open_ostree(
@@ -222,8 +230,12 @@ pub fn generate_mapping(
     repo: &Repo,
     root: &gio::File,
     packages: &Vec<PackageIndex>,
+    volatility: &dyn Volatility,
+    path_translation: &PathTranslation,
 ) -> Result<ObjectMetaSized, anyhow::Error> {
-    let current_build = get_buildtime();
+    let change_id_mode = ChangeIdMode::of_index(packages);
+    let current_build = change_id_mode.current_change(packages);
+    let change_frequencies = volatility.change_frequencies(packages);
     let mut state = MappingBuilder {
         unpackaged_id: Rc::from(MappingBuilder::UNPACKAGED_ID),
         packagemeta: Default::default(),
@@ -246,7 +258,7 @@ pub fn generate_mapping(
 
     for pkg in packages.into_iter() {
         let nevra: Rc<str> = Rc::from(pkg.package.identifier.as_str());
-        let buildtime = *pkg.changes.last().unwrap_or(&current_build);
+        let buildtime = pkg.last_change().unwrap_or(current_build);
         if let Some((lowid, lowtime)) = lowest_change_time.as_mut() {
             if *lowtime > buildtime {
                 *lowid = Rc::clone(&nevra);
@@ -266,22 +278,30 @@ pub fn generate_mapping(
     // package metadata abstracted for ostree.  Note that right now, the package metadata includes
     // both a "unique identifer" and a "human readable name", but for rpm-ostree we're just making
     // those the same thing.
-    for pkg in packages.iter() {
-        let buildtime = *pkg.changes.last().unwrap_or(&current_build);
-        let change_time_offset_secs: u32 = buildtime
+    for (pkg, change_frequency) in packages.iter().zip(change_frequencies) {
+        let buildtime = pkg.last_change().unwrap_or(current_build);
+        let change_time_offset: u32 = buildtime
             .checked_sub(lowest_change_time)
             .unwrap()
             .try_into()
             .unwrap();
-        // Convert to hours, because there's no strong use for caring about the relative difference of builds in terms
-        // of minutes or seconds.
-        let change_time_offset = change_time_offset_secs / (60 * 60);
+        let change_time_offset = match change_id_mode {
+            // Convert to hours, because there's no strong use for caring about the relative difference of builds in terms
+            // of minutes or seconds.
+            ChangeIdMode::Timestamp => change_time_offset / (60 * 60),
+            // Build numbers already are as coarse as it gets
+            ChangeIdMode::BuildNumber => change_time_offset,
+        };
+        // Overrides from postprocessing take precedence over the history
+        let overrides = &pkg.package.overrides;
         state.packagemeta.insert(ObjectSourceMeta {
             identifier: Rc::from(pkg.package.identifier.as_str()),
             name: Rc::from(pkg.package.name.as_str()),
             srcid: Rc::from(pkg.package.source.as_str()),
-            change_time_offset,
-            change_frequency: pkg.change_frequency(),
+            change_time_offset: overrides.change_time_offset.unwrap_or(change_time_offset),
+            change_frequency: overrides
+                .change_frequency
+                .map_or(change_frequency, |class| class.change_frequency()),
         });
     }
 
@@ -326,34 +346,43 @@ pub fn generate_mapping(
         }
     }
 
+    let mut translated_files = 0usize;
+    let mut unresolved_files = 0usize;
     {
         // Walk each package, adding mappings for each of the files it provides
         let mut dir_cache: HashMap<Utf8PathBuf, ResolvedOstreePaths> = HashMap::new();
+        let fsroot = root.downcast_ref::<ostree::RepoFile>().unwrap();
         for pkg in packages.into_iter() {
             for path in pkg.package.files.iter() {
-                // Resolve the path to its ostree file
-                if let Some(ostree_paths) = fsutil::resolve_ostree_paths(
-                    &path,
-                    root.downcast_ref::<ostree::RepoFile>().unwrap(),
-                    &mut dir_cache,
-                ) {
-                    if ostree_paths.path.is_regular() || ostree_paths.path.is_symlink() {
-                        let real_path =
-                            Utf8PathBuf::from_path_buf(ostree_paths.path.peek_path().unwrap())
-                                .unwrap();
-                        let checksum = ostree_paths.path.checksum().to_string();
-
-                        state
-                            .checksum_paths
-                            .entry(checksum)
-                            .or_default()
-                            .insert(real_path.clone());
-                        state
-                            .path_packages
-                            .entry(real_path)
-                            .or_default()
-                            .insert(Rc::from(pkg.package.identifier.as_str()));
-                    }
+                // Resolve the path to its ostree file, falling back to where the file was moved to in the commit
+                let ostree_paths = fsutil::resolve_ostree_paths(&path, fsroot, &mut dir_cache)
+                    .or_else(|| {
+                        let translated = path_translation.translate(&path)?;
+                        let ostree_paths =
+                            fsutil::resolve_ostree_paths(&translated, fsroot, &mut dir_cache)?;
+                        translated_files += 1;
+                        Some(ostree_paths)
+                    });
+                let Some(ostree_paths) = ostree_paths else {
+                    tracing::trace!("{} of {} not found in the commit", path, pkg.package.name);
+                    unresolved_files += 1;
+                    continue;
+                };
+                if ostree_paths.path.is_regular() || ostree_paths.path.is_symlink() {
+                    let real_path =
+                        Utf8PathBuf::from_path_buf(ostree_paths.path.peek_path().unwrap()).unwrap();
+                    let checksum = ostree_paths.path.checksum().to_string();
+
+                    state
+                        .checksum_paths
+                        .entry(checksum)
+                        .or_default()
+                        .insert(real_path.clone());
+                    state
+                        .path_packages
+                        .entry(real_path)
+                        .or_default()
+                        .insert(Rc::from(pkg.package.identifier.as_str()));
                 }
             }
         }
@@ -373,11 +402,21 @@ pub fn generate_mapping(
     );
     println!("rpm size: {}", state.rpmsize);
     println!(
-        "Earliest changed package: {} at {}",
-        lowest_change_name,
-        Utc.timestamp_opt(lowest_change_time.try_into().unwrap(), 0)
-            .unwrap()
+        "Packaged files: {} found at translated paths, {} not found",
+        translated_files, unresolved_files
     );
+    match change_id_mode {
+        ChangeIdMode::Timestamp => println!(
+            "Earliest changed package: {} at {}",
+            lowest_change_name,
+            Utc.timestamp_opt(lowest_change_time.try_into().unwrap(), 0)
+                .unwrap()
+        ),
+        ChangeIdMode::BuildNumber => println!(
+            "Earliest changed package: {} in build {}",
+            lowest_change_name, lowest_change_time
+        ),
+    }
     println!("Duplicates: {}", state.duplicate_objects().count());
     for (contentid, paths) in state.duplicate_objects() {
         tracing::trace!(
@@ -409,6 +448,76 @@ pub fn generate_mapping(
     ObjectMetaSized::compute_sizes(&repo, meta)
 }
 
+/// Packages whose size differs by less than this many bytes are never reported as discrepancies.
+const MIN_SIZE_DISCREPANCY: u64 = 1024 * 1024;
+
+/// A package whose size according to the package manager differs from the content it owns in the commit.
+#[derive(Debug)]
+pub struct SizeDiscrepancy {
+    pub name: String,
+    /// Size according to the package manager (or postprocessing)
+    pub recorded: u64,
+    /// Total size of the content objects attributed to the package by [`generate_mapping`]
+    pub actual: u64,
+}
+
+impl SizeDiscrepancy {
+    fn difference(&self) -> u64 {
+        self.recorded.abs_diff(self.actual)
+    }
+}
+
+/// Replace the sizes in `packages` with the sizes of the content objects they own in the commit.
+///
+/// Objects shared by several files or packages are only counted once, for the package they were attributed to.
+/// Packages without any content in the commit end up with a size of zero. Returns the packages whose size changed
+/// by more than `threshold` percent, largest difference first.
+pub fn reconcile_sizes(
+    packages: &mut [PackageIndex],
+    meta: &ObjectMetaSized,
+    threshold: u32,
+) -> Vec<SizeDiscrepancy> {
+    let actual_sizes = meta
+        .sizes
+        .iter()
+        .map(|sized| (sized.meta.identifier.as_ref(), sized.size))
+        .collect::<HashMap<&str, u64>>();
+    let mut discrepancies = Vec::new();
+    for pkg in packages.iter_mut() {
+        let actual = actual_sizes
+            .get(pkg.package.identifier.as_str())
+            .copied()
+            .unwrap_or_default();
+        let discrepancy = SizeDiscrepancy {
+            name: pkg.package.name.clone(),
+            recorded: pkg.package.size,
+            actual,
+        };
+        if discrepancy.difference() >= MIN_SIZE_DISCREPANCY
+            && u128::from(discrepancy.difference()) * 100
+                > u128::from(threshold) * u128::from(discrepancy.recorded)
+        {
+            discrepancies.push(discrepancy);
+        }
+        pkg.package.size = actual;
+    }
+    discrepancies.sort_by(|a, b| b.difference().cmp(&a.difference()));
+
+    let recorded: u64 = discrepancies.iter().map(|d| d.recorded).sum();
+    let actual: u64 = discrepancies.iter().map(|d| d.actual).sum();
+    println!(
+        "Size reconciliation: {} packages differ by more than {}% ({} bytes recorded, {} bytes in the commit)",
+        discrepancies.len(),
+        threshold,
+        recorded,
+        actual
+    );
+    discrepancies
+}
+
+/// Commit metadata key of the composefs digest, also exposed as image label
+pub(crate) const COMPOSEFS_DIGEST_KEY: &str = "ostree.composefs.digest.v0";
+
 /// Like `ostree container encapsulate`, but uses chunks derived from package data.
 pub fn container_encapsulate(
     opt: ContainerEncapsulateOpts,
@@ -424,7 +533,7 @@ pub fn container_encapsulate(
         })?;
     }
     // TODO: Put this in a public API in ostree-rs-ext?
-    let labels = opt
+    let mut labels: BTreeMap<String, String> = opt
         .labels
         .into_iter()
         .map(|l| {
@@ -451,6 +560,20 @@ pub fn container_encapsulate(
         .chain(std::iter::once("rpmostree.inputhash".to_owned()))
         .collect();
 
+    // The composefs digest is binary, so unlike the other metadata it can't be copied with copy_meta_keys
+    let (commit, _state) = repo.load_commit(rev.as_str())?;
+    let commit_meta = glib::VariantDict::new(Some(&commit.child_value(0)));
+    if let Some(digest) = commit_meta.lookup_value(COMPOSEFS_DIGEST_KEY, None) {
+        let digest = digest
+            .fixed_array::<u8>()
+            .context("Parsing composefs digest")?
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        println!("Composefs digest: {}", digest);
+        labels.insert(COMPOSEFS_DIGEST_KEY.to_string(), digest);
+    }
+
     let config = Config {
         labels: Some(labels),
         cmd: opt.cmd,
@@ -1,6 +1,7 @@
 //! Helpers for interacting with containers-storage via forking podman.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
+use std::os::unix::process::CommandExt;
 use std::process::{Command, Stdio};
 
 use anyhow::{Context, Result};
@@ -9,20 +10,47 @@ use fn_error_context::context;
 
 use crate::rpm_ostree::cmdutils::CommandRunExt;
 
-// /// Ensure that we're in a new user+mountns, so that "buildah mount"
-// /// will work reliably.
-// /// https://github.com/containers/buildah/issues/5976
-// #[allow(dead_code)]
-// pub(crate) fn reexec_if_needed() -> Result<()> {
-//     if ostree_ext::container_utils::running_in_container() {
-//         crate::reexec::reexec_with_guardenv(
-//             "_RPMOSTREE_REEXEC_USERNS",
-//             &["unshare", "-U", "-m", "--map-root-user", "--keep-caps"],
-//         )
-//     } else {
-//         Ok(())
-//     }
-// }
+/// Set in the environment of the re-executed process, to not re-execute it again
+const REEXEC_GUARD_ENV: &str = "_OCI_CHUNKER_REEXEC_USERNS";
+/// Set by `podman unshare` and `buildah unshare`
+const CONTAINERS_USERNS_ENV: &str = "_CONTAINERS_USERNS_CONFIGURED";
+
+/// Replace the current process by itself, run through `wrapper`.
+fn reexec_with_guardenv(wrapper: &[&str]) -> Result<()> {
+    let exe = std::env::current_exe().context("Locating executable")?;
+    tracing::debug!("Re-executing {} via {}", exe.display(), wrapper.join(" "));
+    let err = Command::new(wrapper[0])
+        .args(&wrapper[1..])
+        .arg(exe)
+        .args(std::env::args_os().skip(1))
+        .env(REEXEC_GUARD_ENV, "1")
+        .exec();
+    Err(anyhow::Error::new(err).context(format!("Re-executing via {}", wrapper[0])))
+}
+
+/// Ensure that we're in a user and mount namespace in which "podman mount" and "buildah mount" work.
+///
+/// Rootless, this is the namespace of the container storage, entered with "podman unshare". Running as root inside
+/// an unprivileged container, a new user+mountns is needed, see https://github.com/containers/buildah/issues/5976
+///
+/// There is no automated test for this, as it needs podman or buildah with a pulled image. To check it manually, run
+/// `generate-ostree-repo --from <image>` and `build-package-index --image <image>` as an unprivileged user with an
+/// image pulled by that user, and as root inside `podman run --rm -it` without `--privileged`.
+pub(crate) fn reexec_if_needed() -> Result<()> {
+    if std::env::var_os(REEXEC_GUARD_ENV).is_some()
+        || std::env::var_os(CONTAINERS_USERNS_ENV).is_some()
+    {
+        return Ok(());
+    }
+    if !rustix::process::geteuid().is_root() {
+        let backend = Mount::detect_backend()?;
+        reexec_with_guardenv(&[backend.as_ref(), "unshare"])
+    } else if ostree_ext::container_utils::running_in_container() {
+        reexec_with_guardenv(&["unshare", "-U", "-m", "--map-root-user", "--keep-caps"])
+    } else {
+        Ok(())
+    }
+}
 
 /// We need to handle containers that only have podman, not buildah (like the -bootc ones)
 /// as well as the inverse (like the buildah container).
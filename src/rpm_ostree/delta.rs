@@ -0,0 +1,169 @@
+//! Generation of ostree static deltas between commits
+
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Parser;
+use ostree_ext::{gio, ostree};
+
+use crate::rpm_ostree::container::compare_builds;
+
+/// Generate an ostree static delta between two commits and report its size.
+#[derive(Debug, Parser)]
+pub(crate) struct GenerateStaticDeltaOpts {
+    /// Path to the OSTree repository
+    #[clap(long)]
+    repo: Utf8PathBuf,
+
+    /// Target ref or commit of the delta
+    #[clap(long)]
+    to: String,
+
+    /// Source ref or commit of the delta, defaults to the parent of the target commit
+    #[clap(long, conflicts_with = "empty")]
+    from: Option<String>,
+
+    /// Generate a delta from scratch, containing the whole target commit
+    #[clap(long)]
+    empty: bool,
+
+    /// Previous container image (imgref) to compare the OCI layer update cost with
+    #[clap(long, requires = "imgref")]
+    compare_with_build: Option<String>,
+
+    /// Container image (imgref) built from the target commit
+    #[clap(long, requires = "compare_with_build")]
+    imgref: Option<String>,
+}
+
+/// Encode a checksum in the modified base64 form ostree uses for delta paths (`/` replaced by `_`, no padding).
+fn checksum_to_b64(checksum: &str) -> Result<String> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+_";
+    if checksum.len() != 64 || !checksum.bytes().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!("Invalid checksum {}", checksum);
+    }
+    let bytes = (0..checksum.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&checksum[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()?;
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let value = chunk.iter().enumerate().fold(0u32, |value, (i, byte)| {
+            value | (u32::from(*byte) << (16 - 8 * i))
+        });
+        for i in 0..=chunk.len() {
+            encoded.push(char::from(
+                ALPHABET[((value >> (18 - 6 * i)) & 0x3f) as usize],
+            ));
+        }
+    }
+    Ok(encoded)
+}
+
+/// Path of a static delta relative to the repository, see `_ostree_get_relative_static_delta_path`.
+fn static_delta_path(from: Option<&str>, to: &str) -> Result<Utf8PathBuf> {
+    let to = checksum_to_b64(to)?;
+    let name = match from {
+        Some(from) => {
+            let from = checksum_to_b64(from)?;
+            format!("{}/{}-{}", &from[..2], &from[2..], to)
+        }
+        None => format!("{}/{}", &to[..2], &to[2..]),
+    };
+    Ok(Utf8Path::new("deltas").join(name))
+}
+
+/// Total size of the superblock and all parts of a delta.
+fn directory_size(path: &Utf8Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in path.read_dir_utf8()? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Generate a static delta between `from` (or from scratch) and `to`, returning its size in bytes.
+pub(crate) fn generate_static_delta(
+    repo_path: &Utf8Path,
+    repo: &ostree::Repo,
+    from: Option<&str>,
+    to: &str,
+) -> Result<u64> {
+    println!(
+        "Generating static delta {}-{}",
+        from.unwrap_or("(empty)"),
+        to
+    );
+    repo.static_delta_generate(
+        ostree::RepoStaticDeltaGenerateOpt::Major,
+        from,
+        to,
+        None,
+        None,
+        gio::Cancellable::NONE,
+    )
+    .context("Generating static delta")?;
+    directory_size(&repo_path.join(static_delta_path(from, to)?))
+        .context("Determining static delta size")
+}
+
+impl GenerateStaticDeltaOpts {
+    pub(crate) fn run(&self) -> Result<()> {
+        let repo = ostree_ext::cli::parse_repo(&self.repo)?;
+        let to = repo.require_rev(&self.to)?;
+        let from = match (&self.from, self.empty) {
+            (Some(from), _) => Some(repo.require_rev(from)?),
+            (None, true) => None,
+            (None, false) => {
+                let (commit, _state) = repo.load_commit(&to)?;
+                let parent = ostree::commit_get_parent(&commit).ok_or_else(|| {
+                    anyhow::anyhow!("Commit {} has no parent, use --from or --empty", to)
+                })?;
+                repo.load_commit(&parent).with_context(|| {
+                    format!(
+                        "Loading parent commit {}, which may have been pruned; generate the delta with \
+                         `generate-ostree-repo --static-delta` before pruning",
+                        parent
+                    )
+                })?;
+                Some(parent)
+            }
+        };
+        let delta_size = generate_static_delta(&self.repo, &repo, from.as_deref(), &to)?;
+        println!("Static delta size: {}", delta_size);
+
+        if let (Some(compare_with_build), Some(imgref)) = (&self.compare_with_build, &self.imgref) {
+            let handle = tokio::runtime::Handle::current();
+            let (added_layers, added_size) =
+                handle.block_on(async { compare_builds(compare_with_build, imgref).await })?;
+            println!(
+                "Update cost: {} bytes in {} OCI layers, {} bytes as ostree static delta",
+                added_size, added_layers, delta_size
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_static_delta_path() {
+        let from = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        let to = "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff";
+        assert_eq!(
+            static_delta_path(None, to).unwrap(),
+            "deltas/__/________________________________________8"
+        );
+        assert_eq!(
+            static_delta_path(Some(from), to).unwrap(),
+            "deltas/AS/NFZ4mrze8BI0VniavN7wEjRWeJq83vASNFZ4mrze8-__________________________________________8"
+        );
+    }
+}
@@ -112,6 +112,72 @@ pub fn resolve_ostree_paths(
     Some(result)
 }
 
+/// Rewrites the paths recorded by package managers to where the files live in an ostree commit.
+///
+/// Each rule maps a path prefix to its replacement, the longest matching prefix wins. Untranslated paths are
+/// always tried first, so rules only apply to files that cannot be found at their original location.
+#[derive(Debug, Clone, Default)]
+pub struct PathTranslation {
+    rules: Vec<(Utf8PathBuf, Utf8PathBuf)>,
+}
+
+impl PathTranslation {
+    /// `/etc` is moved to `/usr/etc` when generating the commit, `/opt` and the package manager databases are
+    /// relocated below `/usr` by image builders, and usr-merge turns the toplevel directories into aliases.
+    pub const DEFAULT_RULES: &'static [(&'static str, &'static str)] = &[
+        ("/etc", "/usr/etc"),
+        ("/opt", "/usr/lib/opt"),
+        ("/var/lib/rpm", "/usr/lib/sysimage/rpm"),
+        ("/var/lib/pacman", "/usr/lib/sysimage/pacman"),
+        ("/bin", "/usr/bin"),
+        ("/sbin", "/usr/sbin"),
+        ("/lib", "/usr/lib"),
+        ("/lib64", "/usr/lib64"),
+    ];
+
+    /// Create a translation from `FROM=TO` rules, optionally on top of [`Self::DEFAULT_RULES`].
+    pub fn new(rules: &[String], with_defaults: bool) -> anyhow::Result<Self> {
+        let mut translation = PathTranslation::default();
+        if with_defaults {
+            for (from, to) in Self::DEFAULT_RULES {
+                translation.add_rule(Utf8PathBuf::from(*from), Utf8PathBuf::from(*to));
+            }
+        }
+        for rule in rules {
+            let (from, to) = rule
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("Missing '=' in path translation {}", rule))?;
+            let (from, to) = (Utf8PathBuf::from(from), Utf8PathBuf::from(to));
+            if !from.is_absolute() || !to.is_absolute() {
+                anyhow::bail!("Path translation {} must use absolute paths", rule);
+            }
+            translation.add_rule(from, to);
+        }
+        Ok(translation)
+    }
+
+    /// Add a rule, replacing an existing one for the same prefix.
+    fn add_rule(&mut self, from: Utf8PathBuf, to: Utf8PathBuf) {
+        self.rules.retain(|(existing, _to)| *existing != from);
+        self.rules.push((from, to));
+    }
+
+    /// Translate a path according to the rule with the longest matching prefix, if any.
+    pub fn translate(&self, path: &Utf8Path) -> Option<Utf8PathBuf> {
+        self.rules
+            .iter()
+            .filter_map(|(from, to)| path.strip_prefix(from).ok().map(|rest| (from, to, rest)))
+            .max_by_key(|(from, _to, _rest)| from.components().count())
+            .map(|(_from, to, rest)| {
+                if rest.as_str().is_empty() {
+                    to.clone()
+                } else {
+                    to.join(rest)
+                }
+            })
+    }
+}
+
 pub trait FileHelpers {
     fn is_dir(&self) -> bool;
     fn is_regular(&self) -> bool;
@@ -137,3 +203,65 @@ where
             == gio::FileType::SymbolicLink
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translate(translation: &PathTranslation, path: &str) -> Option<Utf8PathBuf> {
+        translation.translate(Utf8Path::new(path))
+    }
+
+    #[test]
+    fn test_path_translation_defaults() {
+        let translation = PathTranslation::new(&[], true).unwrap();
+        assert_eq!(
+            translate(&translation, "/etc").as_deref(),
+            Some(Utf8Path::new("/usr/etc"))
+        );
+        assert_eq!(
+            translate(&translation, "/etc/os-release").as_deref(),
+            Some(Utf8Path::new("/usr/etc/os-release"))
+        );
+        // Prefixes only match whole path components
+        assert_eq!(translate(&translation, "/etcfoo/bar"), None);
+        assert_eq!(translate(&translation, "/usr/bin/bash"), None);
+        assert!(
+            translate(
+                &PathTranslation::new(&[], false).unwrap(),
+                "/etc/os-release"
+            )
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn test_path_translation_rules() {
+        let translation = PathTranslation::new(
+            &[
+                "/var=/usr/share/factory/var".to_string(),
+                "/opt=/usr/share/opt".to_string(),
+            ],
+            true,
+        )
+        .unwrap();
+        // The longest prefix wins, regardless of whether the rule is a default one
+        assert_eq!(
+            translate(&translation, "/var/lib/rpm/rpmdb.sqlite").as_deref(),
+            Some(Utf8Path::new("/usr/lib/sysimage/rpm/rpmdb.sqlite"))
+        );
+        assert_eq!(
+            translate(&translation, "/var/lib/foo").as_deref(),
+            Some(Utf8Path::new("/usr/share/factory/var/lib/foo"))
+        );
+        // User rules replace the default rule for the same prefix
+        assert_eq!(
+            translate(&translation, "/opt/app/bin").as_deref(),
+            Some(Utf8Path::new("/usr/share/opt/app/bin"))
+        );
+
+        assert!(PathTranslation::new(&["/opt".to_string()], false).is_err());
+        assert!(PathTranslation::new(&["opt=/usr/lib/opt".to_string()], false).is_err());
+        assert!(PathTranslation::new(&["/opt=usr/lib/opt".to_string()], false).is_err());
+    }
+}
@@ -3,16 +3,23 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 mod cmdutils;
+mod commitfilter;
 mod compose;
 mod container;
 mod containers_storage;
+mod delta;
 mod fsutil;
+mod reproducible;
+mod selinux;
+mod tmpfiles;
 
 use camino::{Utf8Path, Utf8PathBuf};
 use containers_storage::Mount;
 
 pub(crate) use compose::BuildChunkedOCIOpts;
 pub(crate) use container::*;
+pub(crate) use delta::GenerateStaticDeltaOpts;
+pub(crate) use fsutil::PathTranslation;
 
 pub fn run_with_mount<F: FnOnce(&Utf8Path) -> Result<T, anyhow::Error>, T>(
     run_with_mount: F,
@@ -28,13 +35,11 @@ pub fn run_with_mount<F: FnOnce(&Utf8Path) -> Result<T, anyhow::Error>, T>(
         FileSource::Rootfs(rootfs)
     } else {
         let image = from.as_deref().unwrap();
-        // TODO: Fix running this inside unprivileged podman too. We'll likely need
-        // to refactor things into a two-step process where we do the mount+ostree repo commit
-        // in a subprocess that has the "unshare", and then the secondary main process
-        // just reads/operates on that.
+        // Mounting needs privileges, so rootless (or in an unprivileged container) this re-executes the whole
+        // process in a suitable user namespace and doesn't return.
         // Note that this would all be a lot saner with a composefs-native container storage
         // as we could cleanly operate on that, asking c/storage to synthesize one for us.
-        // crate::containers_storage::reexec_if_needed()?;
+        containers_storage::reexec_if_needed()?;
         FileSource::Podman(Mount::new_for_image(image)?)
     };
     let rootfs = match &rootfs_source {
@@ -0,0 +1,164 @@
+//! Filtering of non-deterministic files for reproducible commits
+
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::sync::Mutex;
+
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use ostree_ext::gio;
+use regex::Regex;
+
+use crate::pkgdb::pattern::glob_to_regex;
+
+/// What to do with a file matching a reproducibility filter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum FilterAction {
+    /// Leave the file out of the commit
+    Skip,
+    /// Commit an empty file instead
+    Empty,
+    /// Reset the source timestamp in the header of Python bytecode
+    Pyc,
+}
+
+impl FilterAction {
+    /// Whether the file content has to be rewritten, rather than just being skipped.
+    fn rewrites(&self) -> bool {
+        !matches!(self, FilterAction::Skip)
+    }
+
+    /// Canonicalize the content of a file.
+    pub(crate) fn rewrite(&self, mut content: Vec<u8>) -> Vec<u8> {
+        match self {
+            FilterAction::Skip => content,
+            FilterAction::Empty => Vec::new(),
+            FilterAction::Pyc => {
+                // Since Python 3.7 the header consists of magic number, flags, source mtime and source size. Only
+                // timestamp based bytecode (flags 0) embeds the mtime, which ostree resets to 0 on checkout anyway.
+                if content.len() >= 16 && content[4..8] == [0, 0, 0, 0] {
+                    content[8..12].fill(0);
+                }
+                content
+            }
+        }
+    }
+}
+
+/// Files known to differ between otherwise identical builds.
+pub(crate) const DEFAULT_FILTERS: &[(&str, FilterAction)] = &[
+    ("/etc/machine-id", FilterAction::Empty),
+    ("/var/lib/systemd/random-seed", FilterAction::Skip),
+    ("/var/lib/systemd/credential.secret", FilterAction::Skip),
+    ("/var/cache/ldconfig/aux-cache", FilterAction::Skip),
+    ("/var/log/**", FilterAction::Skip),
+    ("/usr/**/*.pyc", FilterAction::Pyc),
+];
+
+/// Commit filter leaving out or canonicalizing non-deterministic files.
+///
+/// ostree commit filters can only skip files, so files to rewrite are skipped as well and recorded, to be written
+/// separately after the tree was committed.
+#[derive(Debug, Default)]
+pub(crate) struct ReproducibleFilter {
+    rules: Vec<(String, Regex, FilterAction)>,
+    rewrites: Mutex<Vec<(Utf8PathBuf, FilterAction)>>,
+}
+
+impl ReproducibleFilter {
+    /// Create a filter from `GLOB=ACTION` rules, which take precedence over the optional [`DEFAULT_FILTERS`].
+    pub(crate) fn new(rules: &[String], with_defaults: bool) -> Result<Self> {
+        let mut filter = ReproducibleFilter::default();
+        for rule in rules {
+            let (glob, action) = rule
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("Missing '=' in filter {}", rule))?;
+            let action = FilterAction::from_str(action, true)
+                .map_err(|e| anyhow::anyhow!("Invalid action in filter {}: {}", rule, e))?;
+            filter
+                .rules
+                .push((glob.to_string(), glob_to_regex(glob)?, action));
+        }
+        if with_defaults {
+            for (glob, action) in DEFAULT_FILTERS {
+                filter
+                    .rules
+                    .push((glob.to_string(), glob_to_regex(glob)?, *action));
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Whether to leave `path` out of the commit, recording it if its content has to be rewritten.
+    pub(crate) fn skip(&self, path: &Utf8Path, info: &gio::FileInfo) -> bool {
+        let Some((glob, _regex, action)) = self
+            .rules
+            .iter()
+            .find(|(_glob, regex, _action)| regex.is_match(path.as_str()))
+        else {
+            return false;
+        };
+        if action.rewrites() {
+            // Only the content of regular files can be canonicalized
+            if info.file_type() != gio::FileType::Regular {
+                return false;
+            }
+            self.rewrites
+                .lock()
+                .unwrap()
+                .push((path.to_owned(), *action));
+        }
+        tracing::debug!("Filtering {} ({}, {:?})", path, glob, action);
+        true
+    }
+
+    /// Take the files skipped so far, which have to be committed with rewritten content.
+    pub(crate) fn take_rewrites(&self) -> Vec<(Utf8PathBuf, FilterAction)> {
+        std::mem::take(&mut *self.rewrites.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pyc_rewrite() {
+        let mut pyc = vec![0x6f, 0x0d, 0x0d, 0x0a, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+        pyc.extend_from_slice(b"code");
+        let rewritten = FilterAction::Pyc.rewrite(pyc.clone());
+        assert_eq!(rewritten[8..12], [0, 0, 0, 0]);
+        assert_eq!(rewritten[12..], pyc[12..]);
+
+        // Hash based bytecode stays untouched
+        pyc[4] = 1;
+        assert_eq!(FilterAction::Pyc.rewrite(pyc.clone()), pyc);
+    }
+
+    #[test]
+    fn test_skip() {
+        let filter =
+            ReproducibleFilter::new(&["/usr/lib/*.cache=empty".to_string()], true).unwrap();
+        let info = gio::FileInfo::new();
+        info.set_file_type(gio::FileType::Regular);
+        info.set_attribute_uint32("unix::uid", 1000);
+        info.set_attribute_uint32("unix::gid", 5);
+        assert!(!filter.skip(Utf8Path::new("/usr/bin/write"), &info));
+        assert!(filter.skip(Utf8Path::new("/var/log/dnf.log"), &info));
+        assert!(filter.skip(Utf8Path::new("/usr/lib/foo.cache"), &info));
+        // Ownership is part of the input and stays as it is
+        assert_eq!(info.attribute_uint32("unix::uid"), 1000);
+        assert_eq!(info.attribute_uint32("unix::gid"), 5);
+        assert_eq!(
+            filter.take_rewrites(),
+            [(Utf8PathBuf::from("/usr/lib/foo.cache"), FilterAction::Empty)]
+        );
+
+        // Only regular files are rewritten, other entries are kept as they are
+        let dir = gio::FileInfo::new();
+        dir.set_file_type(gio::FileType::Directory);
+        assert!(!filter.skip(Utf8Path::new("/usr/lib/bar.cache"), &dir));
+        assert!(filter.take_rewrites().is_empty());
+    }
+}
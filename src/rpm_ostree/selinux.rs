@@ -0,0 +1,151 @@
+//! SELinux labeling of committed content
+
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::os::fd::{AsFd, AsRawFd};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std::fs::Dir;
+use fn_error_context::context;
+use ostree_ext::glib::prelude::*;
+use ostree_ext::{gio, glib, ostree};
+
+const SELINUX_XATTR: &[u8] = b"security.selinux\0";
+
+pub(crate) fn label_to_xattrs(label: Option<&str>) -> Option<glib::Variant> {
+    let xattrs = label.map(|label| {
+        let mut label: Vec<_> = label.to_owned().into();
+        label.push(0);
+        vec![(SELINUX_XATTR, label)]
+    });
+    xattrs.map(|x| x.to_variant())
+}
+
+/// The xattrs holding the label of `path` (absolute in the commit), if labeling.
+pub(crate) fn selinux_xattrs(
+    policy: Option<&ostree::SePolicy>,
+    path: &str,
+    mode: u32,
+) -> Result<Option<glib::Variant>> {
+    let label = match policy {
+        Some(policy) => policy.label(path, mode, gio::Cancellable::NONE)?,
+        None => None,
+    };
+    Ok(label_to_xattrs(label.as_deref()))
+}
+
+/// Load the SELinux policy of `policy_root`, or of the rootfs if not given.
+///
+/// Returns `None` if labeling is disabled, or if the rootfs has no policy as on e.g. Arch Linux.
+#[context("Loading SELinux policy")]
+pub(crate) fn load_policy(
+    rootfs: &Dir,
+    policy_root: Option<&Utf8Path>,
+    disabled: bool,
+) -> Result<Option<ostree::SePolicy>> {
+    if disabled {
+        println!("SELinux labeling disabled");
+        return Ok(None);
+    }
+    let policy_root_dir = policy_root
+        .map(|path| {
+            Dir::open_ambient_dir(path, cap_std::ambient_authority())
+                .with_context(|| format!("Opening {}", path))
+        })
+        .transpose()?;
+    let policy = ostree::SePolicy::new_at(
+        policy_root_dir
+            .as_ref()
+            .unwrap_or(rootfs)
+            .as_fd()
+            .as_raw_fd(),
+        gio::Cancellable::NONE,
+    )?;
+    match policy.name().filter(|name| !name.is_empty()) {
+        Some(name) => {
+            println!("Labeling with SELinux policy {}", name);
+            Ok(Some(policy))
+        }
+        None => {
+            if let Some(path) = policy_root {
+                anyhow::bail!("No SELinux policy found in {}", path);
+            }
+            println!("No SELinux policy found in the rootfs, not labeling");
+            Ok(None)
+        }
+    }
+}
+
+fn has_selinux_label(xattrs: &glib::Variant) -> bool {
+    xattrs
+        .iter()
+        .any(|xattr| xattr.child_value(0).data_as_bytes().as_ref() == SELINUX_XATTR)
+}
+
+fn collect_unlabeled(
+    file: &gio::File,
+    path: &Utf8Path,
+    unlabeled: &mut Vec<Utf8PathBuf>,
+) -> Result<()> {
+    let cancellable = gio::Cancellable::NONE;
+    let repo_file = file
+        .downcast_ref::<ostree::RepoFile>()
+        .ok_or_else(|| anyhow::anyhow!("{} is not in an ostree repository", path))?;
+    repo_file.ensure_resolved()?;
+    if !has_selinux_label(&repo_file.xattrs(cancellable)?) {
+        unlabeled.push(path.to_owned());
+    }
+    if file.query_file_type(gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS, cancellable)
+        != gio::FileType::Directory
+    {
+        return Ok(());
+    }
+    let children = file.enumerate_children(
+        "standard::name",
+        gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+        cancellable,
+    )?;
+    while let Some(info) = children.next_file(cancellable)? {
+        let name = info.name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Non-UTF-8 name in {}", path))?;
+        collect_unlabeled(&file.child(name), &path.join(name), unlabeled)?;
+    }
+    Ok(())
+}
+
+/// Walk a commit and fail if any file, directory or symlink in it lacks an SELinux label.
+#[context("Verifying SELinux labels")]
+pub(crate) fn verify_selinux_labels(repo: &ostree::Repo, commit: &str) -> Result<()> {
+    let (root, _checksum) = repo.read_commit(commit, gio::Cancellable::NONE)?;
+    let mut unlabeled = Vec::new();
+    collect_unlabeled(&root, Utf8Path::new("/"), &mut unlabeled)?;
+    if unlabeled.is_empty() {
+        println!("All paths of commit {} are labeled", commit);
+        return Ok(());
+    }
+    for path in &unlabeled {
+        println!("Unlabeled: {}", path);
+    }
+    anyhow::bail!("Found {} paths without SELinux label", unlabeled.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selinux_xattrs() {
+        let xattrs = label_to_xattrs(Some("system_u:object_r:usr_t:s0")).unwrap();
+        assert!(has_selinux_label(&xattrs));
+        let empty: Vec<(&[u8], Vec<u8>)> = Vec::new();
+        assert!(!has_selinux_label(&empty.to_variant()));
+        assert!(
+            selinux_xattrs(None, "/usr", libc::S_IFDIR | 0o755)
+                .unwrap()
+                .is_none()
+        );
+    }
+}
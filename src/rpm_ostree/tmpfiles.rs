@@ -0,0 +1,293 @@
+//! Conversion of `/var` content into tmpfiles.d entries; corresponds to `convert_var_to_tmpfiles_d` in rpm-ostree
+
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std::fs::{Dir, MetadataExt};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::gio;
+
+use crate::rpm_ostree::commitfilter::CommitFilter;
+
+/// Directory of the generated tmpfiles.d configuration, relative to the root
+pub(crate) const AUTOVAR_DIR: &str = "usr/lib/tmpfiles.d";
+/// Name of the generated tmpfiles.d configuration
+pub(crate) const AUTOVAR_NAME: &str = "oci-chunker-autovar.conf";
+
+/// Map user or group IDs to names, using a `passwd` or `group` style file in the rootfs.
+fn read_id_names(rootfs: &Dir, paths: &[&str]) -> HashMap<u32, String> {
+    let mut names = HashMap::new();
+    for path in paths {
+        let Some(contents) = rootfs.read_to_string(path).ok() else {
+            continue;
+        };
+        for line in contents.lines() {
+            let fields = line.split(':').collect::<Vec<_>>();
+            if let (Some(name), Some(Ok(id))) = (fields.first(), fields.get(2).map(|id| id.parse()))
+            {
+                names.entry(id).or_insert_with(|| name.to_string());
+            }
+        }
+    }
+    names
+}
+
+/// Collect the paths already configured by the tmpfiles.d snippets in the rootfs.
+fn existing_tmpfiles_paths(rootfs: &Dir) -> Result<BTreeSet<String>> {
+    let mut paths = BTreeSet::new();
+    for dir in ["usr/lib/tmpfiles.d", "etc/tmpfiles.d"] {
+        let Some(dir) = rootfs.open_dir_optional(dir)? else {
+            continue;
+        };
+        for entry in dir.entries_utf8()? {
+            let name = entry?.file_name()?;
+            if !name.ends_with(".conf") {
+                continue;
+            }
+            let contents = dir
+                .read_to_string(&name)
+                .with_context(|| format!("Reading tmpfiles.d snippet {name}"))?;
+            paths.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .filter_map(|line| line.split_whitespace().nth(1))
+                    .map(str::to_string),
+            );
+        }
+    }
+    Ok(paths)
+}
+
+struct Converter<'a> {
+    // Paths left out of the commit are not recreated either
+    filter: &'a CommitFilter,
+    users: HashMap<u32, String>,
+    groups: HashMap<u32, String>,
+    existing: BTreeSet<String>,
+    entries: Vec<String>,
+}
+
+impl Converter<'_> {
+    fn user(&self, uid: u32) -> String {
+        self.users
+            .get(&uid)
+            .cloned()
+            .unwrap_or_else(|| uid.to_string())
+    }
+
+    fn group(&self, gid: u32) -> String {
+        self.groups
+            .get(&gid)
+            .cloned()
+            .unwrap_or_else(|| gid.to_string())
+    }
+
+    fn push(&mut self, path: &Utf8Path, entry: String) {
+        if self.existing.contains(path.as_str()) {
+            tracing::debug!("{} is already configured in tmpfiles.d", path);
+            return;
+        }
+        tracing::info!("Converting {} to tmpfiles.d entry: {}", path, entry);
+        self.entries.push(entry);
+    }
+
+    fn convert_dir(&mut self, dir: &Dir, path: &Utf8Path) -> Result<()> {
+        let mut names = dir
+            .entries_utf8()?
+            .map(|entry| Ok(entry?.file_name()?))
+            .collect::<Result<Vec<String>>>()?;
+        // Keep the output reproducible
+        names.sort();
+        for name in names {
+            let child_path = path.join(&name);
+            // tmpfiles.d splits on whitespace, so such paths would need quoting
+            if child_path.as_str().contains(char::is_whitespace) {
+                tracing::warn!("Ignoring {}, which contains whitespace", child_path);
+                continue;
+            }
+            let meta = dir
+                .symlink_metadata(&name)
+                .with_context(|| format!("Querying {child_path}"))?;
+            let file_type = if meta.is_dir() {
+                gio::FileType::Directory
+            } else if meta.is_symlink() {
+                gio::FileType::SymbolicLink
+            } else {
+                tracing::warn!(
+                    "Ignoring {}, only directories and symlinks can be converted to tmpfiles.d entries",
+                    child_path
+                );
+                continue;
+            };
+            if self.filter.exclude_path(&child_path, file_type) {
+                continue;
+            }
+            if meta.is_dir() {
+                let entry = format!(
+                    "d {} {:04o} {} {} - -",
+                    child_path,
+                    meta.mode() & 0o7777,
+                    self.user(meta.uid()),
+                    self.group(meta.gid())
+                );
+                self.push(&child_path, entry);
+                let child = dir
+                    .open_dir(&name)
+                    .with_context(|| format!("Opening {child_path}"))?;
+                self.convert_dir(&child, &child_path)?;
+            } else {
+                let target: Utf8PathBuf = dir
+                    .read_link_contents(&name)
+                    .with_context(|| format!("Reading {child_path}"))?
+                    .try_into()?;
+                let entry = format!("L {} - - - - {}", child_path, target);
+                self.push(&child_path, entry);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Generate a tmpfiles.d configuration recreating the directories and symlinks below `/var` in the rootfs.
+///
+/// ostree only populates `/var` on the first deployment, so anything shipped there in later images would never
+/// show up on existing systems. Paths left out by `filter` are not converted.
+#[context("Converting /var to tmpfiles.d")]
+pub(crate) fn convert_var_to_tmpfiles_d(rootfs: &Dir, filter: &CommitFilter) -> Result<String> {
+    let mut converter = Converter {
+        filter,
+        users: read_id_names(rootfs, &["etc/passwd", "usr/lib/passwd"]),
+        groups: read_id_names(rootfs, &["etc/group", "usr/lib/group"]),
+        existing: existing_tmpfiles_paths(rootfs)?,
+        entries: Vec::new(),
+    };
+    let var = rootfs.open_dir("var").context("Opening /var")?;
+    converter.convert_dir(&var, Utf8Path::new("/var"))?;
+    println!(
+        "Converted {} entries below /var to tmpfiles.d",
+        converter.entries.len()
+    );
+
+    let mut contents =
+        String::from("# Generated by oci-chunker from the content of /var in the image\n");
+    for entry in converter.entries {
+        contents.push_str(&entry);
+        contents.push('\n');
+    }
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::{PermissionsExt, symlink};
+
+    use cap_std::ambient_authority;
+
+    use super::*;
+    use crate::rpm_ostree::commitfilter::{PathFilter, PathFilterConfig};
+    use crate::rpm_ostree::reproducible::ReproducibleFilter;
+
+    fn rootfs() -> (tempfile::TempDir, Dir) {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        for (dir, mode) in [
+            ("var", 0o755),
+            ("var/cache", 0o700),
+            ("var/cache/dnf", 0o755),
+            ("var/lib", 0o755),
+            ("var/lib/foo", 0o755),
+            ("var/log", 0o755),
+            ("var/log/journal", 0o2755),
+            ("var/with space", 0o755),
+        ] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+            std::fs::set_permissions(root.join(dir), std::fs::Permissions::from_mode(mode))
+                .unwrap();
+        }
+        symlink("../run", root.join("var/run")).unwrap();
+        std::fs::write(root.join("var/lib/foo/data"), "data").unwrap();
+
+        let uid = rustix::process::getuid().as_raw();
+        let gid = rustix::process::getgid().as_raw();
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::fs::write(
+            root.join("etc/passwd"),
+            format!("builder:x:{uid}:{gid}::/:/bin/sh\n"),
+        )
+        .unwrap();
+        std::fs::write(root.join("etc/group"), format!("builders:x:{gid}:\n")).unwrap();
+        std::fs::create_dir_all(root.join("usr/lib/tmpfiles.d")).unwrap();
+        std::fs::write(
+            root.join("usr/lib/tmpfiles.d/foo.conf"),
+            "# Already configured\n\nd /var/lib/foo 0755 root root - -\n",
+        )
+        .unwrap();
+
+        let dir = Dir::open_ambient_dir(root, ambient_authority()).unwrap();
+        (tmp, dir)
+    }
+
+    #[test]
+    fn test_convert_var_to_tmpfiles_d() {
+        let (_tmp, rootfs) = rootfs();
+        let contents = convert_var_to_tmpfiles_d(&rootfs, &CommitFilter::default()).unwrap();
+        // /var/lib/foo is already configured and neither regular files nor paths with whitespace are converted
+        assert_eq!(
+            contents.lines().collect::<Vec<_>>(),
+            [
+                "# Generated by oci-chunker from the content of /var in the image",
+                "d /var/cache 0700 builder builders - -",
+                "d /var/cache/dnf 0755 builder builders - -",
+                "d /var/lib 0755 builder builders - -",
+                "d /var/log 0755 builder builders - -",
+                "d /var/log/journal 2755 builder builders - -",
+                "L /var/run - - - - ../run",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_convert_var_to_tmpfiles_d_unknown_ids() {
+        let (_tmp, rootfs) = rootfs();
+        rootfs.remove_file("etc/passwd").unwrap();
+        rootfs.remove_file("etc/group").unwrap();
+        let contents = convert_var_to_tmpfiles_d(&rootfs, &CommitFilter::default()).unwrap();
+        let uid = rustix::process::getuid().as_raw();
+        let gid = rustix::process::getgid().as_raw();
+        assert!(
+            contents
+                .lines()
+                .any(|line| line == format!("d /var/lib 0755 {uid} {gid} - -"))
+        );
+    }
+
+    #[test]
+    fn test_convert_var_to_tmpfiles_d_filtered() {
+        let (_tmp, rootfs) = rootfs();
+        let config = PathFilterConfig {
+            exclude: vec!["/var/cache/*".to_string()],
+            ..Default::default()
+        };
+        let filter = CommitFilter::new(
+            Some(PathFilter::new(&config).unwrap()),
+            Some(ReproducibleFilter::new(&[], true).unwrap()),
+        );
+        let contents = convert_var_to_tmpfiles_d(&rootfs, &filter).unwrap();
+        // The content of /var/cache is excluded and /var/log/** is skipped in reproducible mode
+        assert_eq!(
+            contents.lines().skip(1).collect::<Vec<_>>(),
+            [
+                "d /var/cache 0700 builder builders - -",
+                "d /var/lib 0755 builder builders - -",
+                "d /var/log 0755 builder builders - -",
+                "L /var/run - - - - ../run",
+            ]
+        );
+    }
+}
@@ -1,6 +1,24 @@
+/// The time of the current build as unix timestamp.
+///
+/// If `SOURCE_DATE_EPOCH` is set, it takes precedence over the system clock.
 pub(crate) fn get_buildtime() -> u64 {
+    if let Some(source_date_epoch) = get_source_date_epoch() {
+        return source_date_epoch;
+    }
     std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
         .as_secs()
 }
+
+/// Parse the `SOURCE_DATE_EPOCH` environment variable, see https://reproducible-builds.org/specs/source-date-epoch/
+pub(crate) fn get_source_date_epoch() -> Option<u64> {
+    let value = std::env::var("SOURCE_DATE_EPOCH").ok()?;
+    match value.parse() {
+        Ok(timestamp) => Some(timestamp),
+        Err(e) => {
+            tracing::warn!("Ignoring invalid SOURCE_DATE_EPOCH {:?}: {}", value, e);
+            None
+        }
+    }
+}